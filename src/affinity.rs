@@ -0,0 +1,100 @@
+//! Pinning threads to cores, with the `affinity` feature.
+//!
+//! Threads start out with the affinity of the thread that spawned them, so pinning a
+//! server thread also pins the threads it goes on to spawn (the std server's
+//! connection threads, hyper's thread pool), and `spawn_pinned` can be used to pin
+//! threads spawned by other crates, like the writer thread of a `HistLog`.
+
+use std::thread;
+use serde::Serialize;
+use slog::Logger;
+
+/// Core a thread was to be pinned to, and whether pinning it took. Saved in the run
+/// metadata (see `crate::write_meta`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Pinned {
+    pub thread: String,
+    pub core: usize,
+    pub applied: bool,
+}
+
+/// Pins the current thread, `thread` in the run metadata, to `core`.
+pub fn pin(thread: &str, core: usize, logger: &Logger) -> Pinned {
+    let applied = bind_thread(core, &logger.new(o!("pinned" => thread.to_string())));
+    Pinned { thread: thread.to_string(), core, applied }
+}
+
+/// Calls `f` on a new thread pinned to `core`, so that threads spawned by `f` start
+/// out pinned to `core` too.
+pub fn spawn_pinned<T, F>(thread: &str, core: usize, logger: &Logger, f: F) -> (T, Pinned)
+    where T: Send + 'static,
+          F: FnOnce() -> T + Send + 'static
+{
+    let (name, logger) = (thread.to_string(), logger.clone());
+    thread::Builder::new().name(format!("pin:{}", thread)).spawn(move || {
+        let pinned = pin(&name, core, &logger);
+        (f(), pinned)
+    }).unwrap().join().unwrap()
+}
+
+/// Pins the current thread to `core`, returning whether it is now running on that
+/// core alone.
+#[cfg(feature = "affinity")]
+pub fn bind_thread(core: usize, logger: &Logger) -> bool {
+    use core_affinity::CoreId;
+
+    let available = core_affinity::get_core_ids().unwrap_or_default();
+    if !available.iter().any(|id| id.id == core) {
+        warn!(logger, "core not available to this thread, not pinning";
+              "core" => core, "available" => format!("{:?}", available.iter().map(|id| id.id).collect::<Vec<_>>()));
+        return false
+    }
+    core_affinity::set_for_current(CoreId { id: core });
+    // `set_for_current` doesn't report failure; check what actually took
+    match core_affinity::get_core_ids() {
+        Some(ref ids) if ids.len() == 1 && ids[0].id == core => {
+            info!(logger, "pinned thread to core"; "core" => core);
+            true
+        }
+        _ => {
+            warn!(logger, "failed to pin thread to core"; "core" => core);
+            false
+        }
+    }
+}
+
+#[cfg(not(feature = "affinity"))]
+pub fn bind_thread(core: usize, logger: &Logger) -> bool {
+    warn!(logger, "built without the `affinity` feature, not pinning thread"; "core" => core);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_pinning_as_json() {
+        let pinned = vec![
+            Pinned { thread: "worker-0".to_string(), core: 2, applied: true },
+            Pinned { thread: "histlog".to_string(), core: 3, applied: false },
+        ];
+        assert_eq!(serde_json::to_string(&pinned).unwrap(),
+                   "[{\"thread\":\"worker-0\",\"core\":2,\"applied\":true},\
+                    {\"thread\":\"histlog\",\"core\":3,\"applied\":false}]");
+        let quoted = Pinned { thread: "a \"b\" é".to_string(), core: 0, applied: false };
+        assert_eq!(serde_json::to_string(&quoted).unwrap(), "{\"thread\":\"a \\\"b\\\" é\",\"core\":0,\"applied\":false}");
+    }
+
+    #[cfg(feature = "affinity")]
+    #[test]
+    fn spawned_threads_inherit_pinning() {
+        let logger = Logger::root(slog::Discard, o!());
+        let core = core_affinity::get_core_ids().unwrap()[0].id;
+        let (inherited, pinned) = spawn_pinned("test", core, &logger, || {
+            thread::spawn(|| core_affinity::get_core_ids().unwrap()).join().unwrap()
+        });
+        assert!(pinned.applied);
+        assert_eq!(inherited.iter().map(|id| id.id).collect::<Vec<_>>(), vec![core]);
+    }
+}
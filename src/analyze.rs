@@ -0,0 +1,214 @@
+#[macro_use]
+extern crate clap;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+use chrono::{DateTime, Utc};
+use http_benchmarks::compare::{self, Bootstrap, Run};
+use http_benchmarks::report::{self, Interval};
+
+fn main() {
+    let args: clap::ArgMatches = clap::App::new("analyze")
+        .version(crate_version!())
+        .about("reads the interval logs saved by the client and server, printing latency percentiles")
+        .arg(clap::Arg::with_name("logs")
+             .help("interval log files, or directories to read those under (e.g. var/hist/, or the \
+                   logs rsynced from several servers)")
+             .value_name("LOG")
+             .multiple(true)
+             .required(true))
+        .arg(clap::Arg::with_name("tag")
+             .long("tag")
+             .short("t")
+             .help("only the intervals of this tag (may be given more than once)")
+             .value_name("TAG")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("from")
+             .long("from")
+             .help("skip the intervals starting less than <SECONDS> after the earliest interval read")
+             .value_name("SECONDS")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("to")
+             .long("to")
+             .help("skip the intervals starting <SECONDS> or more after the earliest interval read")
+             .value_name("SECONDS")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("per-file")
+             .long("per-file")
+             .help("report on each log separately, rather than merging the intervals of the same \
+                   tag across the logs of each series"))
+        .arg(clap::Arg::with_name("over-time")
+             .long("over-time")
+             .help("print the percentiles of every <SECONDS> window (from --from) rather than of \
+                   the whole run")
+             .value_name("SECONDS")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("distribution")
+             .long("distribution")
+             .help("print the percentile distribution of each tag, in HdrHistogram's .hgrm format \
+                   (microseconds), as read by its plotter")
+             .conflicts_with("over-time"))
+        .arg(clap::Arg::with_name("ticks")
+             .long("ticks")
+             .help("--distribution: percentile steps between each halving of the distance to 100%")
+             .value_name("N")
+             .default_value("5")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("baseline")
+             .long("baseline")
+             .help("compare the <LOG>s (the candidate run) with this run, by series and tag: interval \
+                   logs, directories of those, or .json summaries (as saved by --summary-json or next \
+                   to a server's log)")
+             .value_name("PATH")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true)
+             .conflicts_with_all(&["per-file", "over-time", "distribution", "from", "to"])
+             .required(false))
+        .arg(clap::Arg::with_name("threshold")
+             .long("threshold")
+             .help("--baseline: exit with status 2 if a percentile got slower by more than <PERCENT>, \
+                   and significantly so when both runs were read from interval logs")
+             .value_name("PERCENT")
+             .validator(http_benchmarks::validate_uint)
+             .requires("baseline")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("confidence")
+             .long("confidence")
+             .help("--baseline: level of the bootstrap confidence intervals, in percent")
+             .value_name("PERCENT")
+             .default_value("95")
+             .validator(validate_confidence)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("resamples")
+             .long("resamples")
+             .help("--baseline: number of bootstrap resamples of each histogram")
+             .value_name("N")
+             .default_value("1000")
+             .validator(http_benchmarks::validate_positive_uint)
+             .takes_value(true)
+             .required(false))
+        .get_matches();
+
+    let seconds = |name| args.value_of(name).map(|secs| Duration::from_secs(u64::from_str(secs).unwrap()));
+    let tags: Vec<&str> = args.values_of("tag").map(|tags| tags.collect()).unwrap_or_default();
+
+    if let Some(baseline) = args.values_of("baseline") {
+        let read = |paths: Vec<&str>| {
+            let mut run = Run::read(&paths).unwrap_or_else(|e| {
+                eprintln!("failed to read {}: {}", paths.join(" "), e);
+                process::exit(1)
+            });
+            run.tags.retain(|(key, _)| tags.is_empty() || tags.contains(&key.tag.as_str()));
+            run
+        };
+        let (baseline, candidate) = (read(baseline.collect()), read(args.values_of("logs").unwrap().collect()));
+        let bootstrap = Bootstrap {
+            resamples: usize::from_str(args.value_of("resamples").unwrap()).unwrap(),
+            confidence: f64::from_str(args.value_of("confidence").unwrap()).unwrap(),
+            ..Default::default()
+        };
+        let comparison = compare::compare(&baseline, &candidate, &bootstrap);
+        println!("{}", comparison);
+        if let Some(threshold) = args.value_of("threshold") {
+            let regressions = comparison.regressions(f64::from_str(threshold).unwrap());
+            for delta in &regressions {
+                println!("REGRESSION: {} {} {:+.1}% (threshold {}%)", delta.key, delta.stat, delta.relative(), threshold);
+            }
+            if !regressions.is_empty() {
+                process::exit(2)
+            }
+        }
+        return
+    }
+
+    let mut logs: Vec<(PathBuf, Vec<Interval>)> = Vec::new();
+    for path in args.values_of("logs").unwrap() {
+        let found = report::find_logs(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("failed to list interval logs in {}: {}", path, e);
+            process::exit(1)
+        });
+        for log in found {
+            match report::read_log(&log) {
+                Ok(intervals) => logs.push((log, intervals)),
+                Err(e) => {
+                    eprintln!("failed to read interval log: {}", e);
+                    process::exit(1)
+                }
+            }
+        }
+    }
+
+    // time windows are relative to the earliest interval read, of any tag
+    let origin = match logs.iter().flat_map(|(_, intervals)| intervals).map(|interval| interval.start).min() {
+        Some(origin) => origin,
+        None => {
+            eprintln!("no intervals in {} interval log(s)", logs.len());
+            process::exit(1)
+        }
+    };
+    let from = origin + seconds("from").unwrap_or_default();
+    let to = seconds("to").map(|to| origin + to);
+    println!("{} interval log(s), earliest interval starting {}\n", logs.len(), DateTime::<Utc>::from(UNIX_EPOCH + origin));
+
+    let selected = |interval: &&Interval| {
+        (tags.is_empty() || tags.contains(&interval.tag.as_str()))
+            && interval.start >= from
+            && to.is_none_or(|to| interval.start < to)
+    };
+    let groups: Vec<(String, Vec<&Interval>)> = if args.is_present("per-file") {
+        logs.iter()
+            .map(|(log, intervals)| (log.display().to_string(), intervals.iter().filter(selected).collect()))
+            .collect()
+    } else {
+        // the client's round trips and the server's one-way latencies share tags,
+        // so only the logs of the same series are merged
+        let mut by_series: BTreeMap<&str, (Vec<String>, Vec<&Interval>)> = BTreeMap::new();
+        for (log, intervals) in &logs {
+            let (labels, merged) = by_series.entry(report::series(log)).or_default();
+            labels.push(log.display().to_string());
+            merged.extend(intervals.iter().filter(selected));
+        }
+        by_series.into_iter()
+            .map(|(series, (labels, intervals))| (format!("{}:\n{}", series, labels.join("\n")), intervals))
+            .collect()
+    };
+
+    for (label, intervals) in groups {
+        println!("{}\n", label);
+        if let Some(width) = seconds("over-time") {
+            println!("{}", report::over_time_table(&report::over_time(intervals, from, width)));
+        } else if args.is_present("distribution") {
+            let ticks = u32::from_str(args.value_of("ticks").unwrap()).unwrap();
+            for summary in report::summarize(intervals) {
+                println!("{}\n{}", summary.tag, report::distribution(&summary.hist, ticks, 1e3));
+            }
+        } else {
+            println!("{}", report::table(&report::summarize(intervals)));
+        }
+    }
+    println!("(latencies in microseconds)");
+}
+
+fn validate_confidence(s: String) -> Result<(), String> {
+    match f64::from_str(&s) {
+        Ok(confidence) if confidence > 0.0 && confidence < 100.0 => Ok(()),
+        _ => Err(format!("{} isn't a percentage between 0 and 100", s)),
+    }
+}
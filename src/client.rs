@@ -11,13 +11,21 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Instant, Duration};
 use std::str::{self, FromStr};
 use std::io;
+use std::path::Path;
+use std::num::NonZeroU64;
 use slog::{Drain, Logger};
 use pretty_toa::ThousandsSep;
+use signal_hook::consts::{SIGINT, SIGTERM};
 
+/// Parses a TLS client's address, defaulting to the https scheme and port 443.
 fn parse_uri(s: &str) -> Result<http::Uri, http::uri::InvalidUri> {
     let s = if s.starts_with("http") { s.to_string() } else { format!("https://{}", s) };
-    let s = if s.ends_with(":443") { s } else { format!("{}:443", s) };
-    http::Uri::from_str(&s)
+    let uri = http::Uri::from_str(&s)?;
+    if uri.port_part().is_some() { return Ok(uri) }
+    http::Uri::from_str(&format!("{}://{}:443{}",
+                                 uri.scheme_str().unwrap_or("https"),
+                                 uri.authority_part().map(|x| x.as_str()).unwrap_or(""),
+                                 uri.path_and_query().map(|x| x.as_str()).unwrap_or("")))
 }
 
 fn main() {
@@ -36,6 +44,39 @@ fn main() {
              .help("sleep <n> milliseconds between requests")
              .takes_value(true)
              .required(false))
+        .arg(clap::Arg::with_name("rate")
+             .long("rate")
+             .short("r")
+             .help("send requests at a constant <N> per second (open loop), measuring latency \
+                   from each request's intended send time")
+             .value_name("N")
+             .validator(http_benchmarks::validate_positive_uint)
+             .conflicts_with("throttle")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("warmup")
+             .long("warmup")
+             .help("send requests for <SECONDS> before recording any, discarding their samples")
+             .value_name("SECONDS")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("duration")
+             .long("duration")
+             .help("stop sending requests <SECONDS> after the warmup, and exit once every client \
+                   has finished")
+             .value_name("SECONDS")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("requests")
+             .long("requests")
+             .help("stop each client after it has sent <N> requests (after the warmup) and \
+                   received their responses, and exit once every client has finished")
+             .value_name("N")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
         .arg(clap::Arg::with_name("openssl-client")
              .long("openssl-client")
              .short("O")
@@ -43,10 +84,179 @@ fn main() {
                    sending requests to <addr>")
              .takes_value(true)
              .required(false))
+        .arg(clap::Arg::with_name("tls-ciphers")
+             .long("tls-ciphers")
+             .help("openssl and h2 clients: cipher list for TLS 1.2 and below, in openssl's format \
+                   (e.g. 'ECDHE-RSA-AES128-SHA:AES128-SHA')")
+             .value_name("LIST")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("tls-min-version")
+             .long("tls-min-version")
+             .help("openssl and h2 clients: lowest TLS version to negotiate")
+             .value_name("VERSION")
+             .possible_values(&["1.0", "1.1", "1.2", "1.3"])
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("tls-max-version")
+             .long("tls-max-version")
+             .help("openssl and h2 clients: highest TLS version to negotiate")
+             .value_name("VERSION")
+             .possible_values(&["1.0", "1.1", "1.2", "1.3"])
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("tls-resume")
+             .long("tls-resume")
+             .help("openssl client: resume the previous TLS session when reconnecting"))
+        .arg(clap::Arg::with_name("tls-ca")
+             .long("tls-ca")
+             .help("openssl and h2 clients: PEM file of certificate authorities to verify the server with, \
+                   in addition to the system's")
+             .value_name("PATH")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("tls-insecure")
+             .long("tls-insecure")
+             .help("openssl and h2 clients: don't verify the server's certificate")
+             .conflicts_with("tls-ca"))
+        .arg(clap::Arg::with_name("tls-servername")
+             .long("tls-servername")
+             .help("openssl and h2 clients: name to send as SNI and verify the server's certificate against")
+             .value_name("NAME")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("h2-client")
+             .long("h2-client")
+             .short("H")
+             .help("Launch an HTTP/2 client (tcp+tls using rust bindings to openssl, h2 negotiated \
+                   with ALPN), sending requests to <addr>")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("h2-streams")
+             .long("h2-streams")
+             .short("s")
+             .help("number of concurrent streams the h2 client sends requests on, over one connection")
+             .value_name("N")
+             .default_value("1")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("connections")
+             .long("connections")
+             .short("n")
+             .help("raw tcp and openssl clients: number of connections to send requests on \
+                   concurrently, each with --pipeline-depth requests in flight")
+             .value_name("N")
+             .default_value("1")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("threads")
+             .long("threads")
+             .short("T")
+             .help("raw tcp and openssl clients: number of worker threads the connections are \
+                   spread across")
+             .value_name("M")
+             .default_value("1")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("pipeline-depth")
+             .long("pipeline-depth")
+             .short("P")
+             .help("raw tcp and openssl clients: number of requests to keep in flight on each \
+                   connection (http/1.1 pipelining), matching responses in order")
+             .value_name("N")
+             .default_value("1")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("connection-per-request")
+             .long("connection-per-request")
+             .help("raw tcp and openssl clients: open a new connection for every request \
+                   (`Connection: close`), recording the time taken to connect and to handshake \
+                   (tls) as histograms of their own")
+             .takes_value(false)
+             .required(false))
+        .arg(clap::Arg::with_name("cores")
+             .long("cores")
+             .help("pin worker threads to these cores, round robin (e.g. '2,3'; the h2 client's \
+                   thread to the first). requires building with the `affinity` feature")
+             .value_name("LIST")
+             .use_delimiter(true)
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("hist-core")
+             .long("hist-core")
+             .help("pin the histogram writer threads to this core (requires building with the \
+                   `affinity` feature)")
+             .value_name("CORE")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("padding")
+             .long("padding")
+             .short("p")
+             .help("append <BYTES> bytes of padding to each request body")
+             .value_name("BYTES")
+             .default_value("0")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("hist-dir")
+             .long("hist-dir")
+             .short("d")
+             .help("directory to save round-trip histogram log files in")
+             .takes_value(true)
+             .default_value("var/hist/")
+             .value_name("PATH")
+             .required(true))
+        .arg(clap::Arg::with_name("interval")
+             .long("interval")
+             .short("i")
+             .help("interval in seconds between writing log entries")
+             .value_name("SECONDS")
+             .default_value("30")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("summary-json")
+             .long("summary-json")
+             .help("on exit, save the latency percentiles, count and throughput of every tag as json \
+                   to <PATH> (the table is logged either way)")
+             .value_name("PATH")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("calibrate")
+             .long("calibrate")
+             .short("c")
+             .help("before sending requests, exchange <PROBES> timestamp probes with the server \
+                   to estimate clock offset and drift, saved next to the interval log")
+             .value_name("PROBES")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("apply-offset")
+             .long("apply-offset")
+             .short("a")
+             .help("adjust request timestamps by the calibrated clock offset, so servers \
+                   measure one-way latency against their own clock")
+             .requires("calibrate")
+             .takes_value(false)
+             .required(false))
         .get_matches();
 
+    let hist_dir = args.value_of("hist-dir").unwrap();
+    let interval = u64::from_str(args.value_of("interval").unwrap()).unwrap();
+    let interval = Duration::from_secs(interval);
+
+    // the first SIGINT/SIGTERM stops the clients, a second one exits right away
     let stop = Arc::new(AtomicBool::new(false));
-    //let topo = Arc::new(Mutex::new(hwloc::Topology::new()));
+    for &signal in &[SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop)).unwrap();
+        signal_hook::flag::register(signal, Arc::clone(&stop)).unwrap();
+    }
 
     let decorator = slog_term::TermDecorator::new().stdout().force_color().build();
     let drain = slog_term::CompactFormat::new(decorator).use_utc_timestamp().build().fuse();
@@ -62,45 +272,119 @@ fn main() {
                 Duration::from_millis(millis)
             });
 
+    let rate = args.value_of("rate").map(|n| NonZeroU64::from_str(n).unwrap());
+    let seconds = |name| args.value_of(name).map(|secs| Duration::from_secs(u64::from_str(secs).unwrap()));
+
+    let settings = http_benchmarks::clients::Settings {
+        throttle,
+        rate,
+        padding: usize::from_str(args.value_of("padding").unwrap()).unwrap(),
+        hist_dir: hist_dir.to_string(),
+        interval,
+        calibrate: args.value_of("calibrate").map(|n| usize::from_str(n).unwrap()).unwrap_or(0),
+        apply_offset: args.is_present("apply-offset"),
+        connections: usize::from_str(args.value_of("connections").unwrap()).unwrap(),
+        threads: usize::from_str(args.value_of("threads").unwrap()).unwrap(),
+        cores: args.values_of("cores")
+            .map(|cores| cores.map(|core| usize::from_str(core).unwrap()).collect())
+            .unwrap_or_default(),
+        hist_core: args.value_of("hist-core").map(|core| usize::from_str(core).unwrap()),
+        pipeline_depth: usize::from_str(args.value_of("pipeline-depth").unwrap()).unwrap(),
+        connection_per_request: args.is_present("connection-per-request"),
+        warmup: seconds("warmup"),
+        duration: seconds("duration"),
+        requests: args.value_of("requests").map(|n| u64::from_str(n).unwrap()),
+    };
+
     let raw_tcp_client = args.value_of("raw-tcp-client").map(|addr| {
         info!(logger, "launching raw tcp client, sending requests to {}", addr);
-        //let topo = Arc::clone(&topo);
         let stop = Arc::clone(&stop);
-        //raw_tcp_client(addr, 0, topo, stop, &logger)
-        http_benchmarks::clients::raw_tcp_client(addr, stop, throttle, &root)
+        http_benchmarks::clients::raw_tcp_client(addr, stop, &settings, &root)
     });
 
+    let tls = http_benchmarks::clients::TlsOptions {
+        ciphers: args.value_of("tls-ciphers").map(|x| x.to_string()),
+        min_version: args.value_of("tls-min-version").and_then(http_benchmarks::clients::parse_tls_version),
+        max_version: args.value_of("tls-max-version").and_then(http_benchmarks::clients::parse_tls_version),
+        resume: args.is_present("tls-resume"),
+        ca_file: args.value_of("tls-ca").map(|x| x.to_string()),
+        insecure: args.is_present("tls-insecure"),
+        servername: args.value_of("tls-servername").map(|x| x.to_string()),
+    };
+
     let openssl_client = args.value_of("openssl-client").map(|addr_arg| {
-        let addr = parse_uri(addr_arg).expect(&format!("failed to parse --openssl-client uri ('{}')", addr_arg));
+        let addr = parse_uri(addr_arg).unwrap_or_else(|e| panic!("failed to parse --openssl-client uri ('{}'): {:?}", addr_arg, e));
         assert!(addr.authority_part().is_some());
         info!(logger, "launching raw tcp+tls[openssl] client, sending requests to {}", addr);
-        //let topo = Arc::clone(&topo);
         let stop = Arc::clone(&stop);
-        //raw_tcp_client(addr, 0, topo, stop, &logger)
-        http_benchmarks::clients::openssl_client(addr, stop, throttle, &root)
+        http_benchmarks::clients::openssl_client(addr, tls.clone(), stop, &settings, &root)
     });
 
-    info!(logger, "program initialized. press enter key to exit.");
-    let mut keys = String::new();
-    loop {
-        if let Ok(_) = io::stdin().read_line(&mut keys) {
-            break
-        }
+    let h2_client = args.value_of("h2-client").map(|addr_arg| {
+        let addr = parse_uri(addr_arg).unwrap_or_else(|e| panic!("failed to parse --h2-client uri ('{}'): {:?}", addr_arg, e));
+        assert!(addr.authority_part().is_some());
+        let streams = usize::from_str(args.value_of("h2-streams").unwrap()).unwrap();
+        info!(logger, "launching h2+tls[openssl] client, sending requests to {}", addr; "streams" => streams);
+        let stop = Arc::clone(&stop);
+        http_benchmarks::clients::h2_client(addr, streams, tls.clone(), stop, &settings, &root)
+    });
+
+    info!(logger, "program initialized. press enter or ctrl-c to stop the clients (twice to exit at once).");
+
+    // a closed stdin (nohup, systemd, ci) leaves the clients running until a
+    // signal, or the end of --duration/--requests
+    {
+        let stop = Arc::clone(&stop);
+        thread::Builder::new().name("stdin".into()).spawn(move || {
+            let mut keys = String::new();
+            if let Ok(n) = io::stdin().read_line(&mut keys) {
+                if n > 0 { stop.store(true, Ordering::Relaxed) }
+            }
+        }).unwrap();
+    }
+
+    let clients = [&raw_tcp_client, &openssl_client, &h2_client];
+    let finished = || clients.iter().all(|client| client.as_ref().is_none_or(|client| client.is_finished()));
+    while !stop.load(Ordering::Relaxed) && !finished() {
         thread::sleep(Duration::from_millis(10));
     }
 
     info!(logger, "sending terminate signal to worker threads");
     stop.store(true, Ordering::Relaxed);
 
+    let mut reports = Vec::new();
+
     if let Some(client) = raw_tcp_client {
         info!(logger, "joining raw tcp client...");
-        let n_sent = client.join().unwrap();
-        info!(logger, "joined raw tcp client"; "n_sent" => n_sent.thousands_sep());
+        let outcome = client.join().unwrap();
+        let responses = outcome.responses;
+        reports.extend(outcome.report);
+        info!(logger, "joined raw tcp client"; "ok" => responses.ok.thousands_sep(), "errors" => responses.errors().thousands_sep(),
+              "responses" => %responses);
     }
 
     if let Some(client) = openssl_client {
         info!(logger, "joining raw tcp+tls[openssl] client...");
-        let n_sent = client.join().unwrap();
-        info!(logger, "joined raw tcp+tls[openssl] client"; "n_sent" => n_sent.thousands_sep());
+        let outcome = client.join().unwrap();
+        let responses = outcome.responses;
+        reports.extend(outcome.report);
+        info!(logger, "joined raw tcp+tls[openssl] client"; "ok" => responses.ok.thousands_sep(), "errors" => responses.errors().thousands_sep(),
+              "responses" => %responses);
+    }
+
+    if let Some(client) = h2_client {
+        info!(logger, "joining h2+tls[openssl] client...");
+        let outcome = client.join().unwrap();
+        let responses = outcome.responses;
+        reports.extend(outcome.report);
+        info!(logger, "joined h2+tls[openssl] client"; "ok" => responses.ok.thousands_sep(), "errors" => responses.errors().thousands_sep(),
+              "responses" => %responses);
+    }
+
+    if let Some(path) = args.value_of("summary-json") {
+        match http_benchmarks::report::write_json(Path::new(path), &reports) {
+            Ok(()) => info!(logger, "saved latency summary"; "path" => path),
+            Err(e) => error!(logger, "failed to save latency summary: {:?}", e),
+        }
     }
 }
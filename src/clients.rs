@@ -1,305 +1,2148 @@
 use std::thread;
-use std::mem;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::net::{TcpListener, TcpStream, Shutdown};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::net::TcpStream;
+use std::collections::VecDeque;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::time::{Instant, Duration};
-use std::str::{self, FromStr};
+use std::str;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::io::{self, Read, Write};
-use std::collections::HashMap;
-use slog::{Drain, Logger};
-use chrono::{DateTime, Utc};
+use std::ops::AddAssign;
+use std::num::NonZeroU64;
+use slog::Logger;
+use chrono::Utc;
 use pretty_toa::ThousandsSep;
-use openssl::ssl::{SslMethod, SslConnector, HandshakeError};
+use openssl::ssl::{SslMethod, SslConnector, SslStream, HandshakeError};
+use openssl::ssl::{SslSession, SslSessionCacheMode, SslVerifyMode, SslVersion};
+use histlog::HistLog;
+use bytes::Bytes;
+use futures::{future, try_ready, Async, Future, Stream};
+use futures::future::{Either, Loop};
+use tokio_openssl::ConnectConfigurationExt;
+use serde::Serialize;
 
-pub fn raw_tcp_client<A: ToSocketAddrs>(
-    addr: A,
-    //core: usize,
-    //topo: Arc<Mutex<hwloc::Topology>>,
-    stop: Arc<AtomicBool>,
-    throttle: Option<Duration>,
-    logger: &Logger,
-) -> thread::JoinHandle<usize> {
-    let addr: SocketAddr = addr.to_socket_addrs().unwrap().next().unwrap();
-    let logger = logger.new(o!("thread" => "raw tcp", "addr" => format!("{}", addr)));//, "core" => core));
-    thread::spawn(move || {
-        //#[cfg(feature = "affinity")]
-        //bind_thread(topo, core, &logger);
-        let start = Instant::now();
-        let client_code = 11;
-        let mut loop_time: Instant;
-        let mut length: usize = 89;
-        let mut n_sent = 0;
-        let mut n_sent_stream = 0;
-        let mut snd = [0u8; 512];
-        let mut rcv = [0u8; 512];
-        let headers = format!(
+use crate::{affinity, response, wire};
+use crate::affinity::Pinned;
+use crate::clock::{Calibration, Exchange, OffsetEstimator};
+use crate::response::Response;
+use crate::report::Report;
+
+/// Number of exchanges per window of the clock offset estimate used to split the
+/// round trip (see `clock::OffsetEstimator`).
+const OFFSET_WINDOW: u32 = 1_000;
+
+/// Pause between calibration probes, so a calibration run spans long enough to
+/// say something about drift.
+const CALIBRATION_SPACING: Duration = Duration::from_millis(1);
+
+/// Number of groups calibration probes are split into to fit the drift (see
+/// `clock::Calibration::fit`).
+const CALIBRATION_BUCKETS: usize = 20;
+
+/// Largest response the http/1.1 clients buffer; the receive buffer starts at 512
+/// bytes and doubles as needed up to this.
+const MAX_RESPONSE_LEN: usize = 1 << 20;
+
+/// Responses received by a client run (or several of them, added together), by
+/// status class. Only the round trips of successful (2xx) responses are recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Responses {
+    /// 2xx
+    pub ok: usize,
+    /// 3xx, and final 1xx (`101 Switching Protocols`)
+    pub other: usize,
+    /// 4xx
+    pub client_errors: usize,
+    /// 5xx
+    pub server_errors: usize,
+    /// responses that could not be parsed
+    pub invalid: usize,
+    /// requests dropped unanswered when their connection was lost (not counted
+    /// in `total`)
+    pub dropped: usize,
+}
+
+impl Responses {
+    fn count(&mut self, status: u16) {
+        match status / 100 {
+            2 => self.ok += 1,
+            4 => self.client_errors += 1,
+            5 => self.server_errors += 1,
+            1 | 3 => self.other += 1,
+            _ => self.invalid += 1,
+        }
+    }
+
+    /// Responses other than 2xx.
+    pub fn errors(&self) -> usize {
+        self.other + self.client_errors + self.server_errors + self.invalid
+    }
+
+    pub fn total(&self) -> usize {
+        self.ok + self.errors()
+    }
+}
+
+impl AddAssign for Responses {
+    fn add_assign(&mut self, other: Self) {
+        self.ok += other.ok;
+        self.other += other.other;
+        self.client_errors += other.client_errors;
+        self.server_errors += other.server_errors;
+        self.invalid += other.invalid;
+        self.dropped += other.dropped;
+    }
+}
+
+impl fmt::Display for Responses {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ok={} other={} 4xx={} 5xx={} invalid={} dropped={}",
+               self.ok, self.other, self.client_errors, self.server_errors, self.invalid, self.dropped)
+    }
+}
+
+/// What a client run returns once finished.
+pub struct Outcome {
+    pub responses: Responses,
+    /// latency summary of the run's interval log, unless it could not be read back
+    pub report: Option<Report>,
+}
+
+/// Round trip split into request flight, server time and response flight, using the
+/// timestamps echoed by servers run with `--echo-timestamps` and a running estimate
+/// of the offset between the client's and server's clocks.
+struct Split {
+    offset: OffsetEstimator,
+    request_flight: HistLog,
+    server_time: HistLog,
+    response_flight: HistLog,
+}
+
+impl Split {
+    fn new(rtt: &HistLog, tag: &str) -> Self {
+        Self {
+            offset: OffsetEstimator::new(OFFSET_WINDOW),
+            request_flight: rtt.clone_with_tag(crate::leak_tag(format!("{}:request-flight", tag))),
+            server_time: rtt.clone_with_tag(crate::leak_tag(format!("{}:server-time", tag))),
+            response_flight: rtt.clone_with_tag(crate::leak_tag(format!("{}:response-flight", tag))),
+        }
+    }
+
+    /// Records the exchange, returning `true` if the histograms were sent to the
+    /// writer thread.
+    fn record(&mut self, x: &Exchange, loop_time: Instant) -> bool {
+        let offset = self.offset.update(x);
+        self.request_flight.record((x.t2 - x.t1 - offset).max(0) as u64).ok();
+        self.server_time.record((x.t3 - x.t2).max(0) as u64).ok();
+        self.response_flight.record((x.t4 - x.t3 + offset).max(0) as u64).ok();
+        self.server_time.check_send(loop_time);
+        self.response_flight.check_send(loop_time);
+        self.request_flight.check_send(loop_time)
+    }
+
+    /// Discards what was recorded so far (at the end of the warmup).
+    fn reset(&mut self) {
+        self.request_flight.reset();
+        self.server_time.reset();
+        self.response_flight.reset();
+    }
+}
+
+/// Settings shared by every client, applied by the request loop in `run`.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// sleep between requests (closed loop)
+    pub throttle: Option<Duration>,
+    /// send a constant number of requests per second (open loop)
+    pub rate: Option<NonZeroU64>,
+    /// number of padding bytes appended to each request body
+    pub padding: usize,
+    /// directory to save the round-trip histogram log in
+    pub hist_dir: String,
+    /// interval between writing log entries
+    pub interval: Duration,
+    /// number of clock calibration probes sent before the benchmark (0 to skip)
+    pub calibrate: usize,
+    /// add the calibrated clock offset to the `sent` timestamp of each request, so
+    /// servers measure one-way latency against their own clock
+    pub apply_offset: bool,
+    /// number of connections requests are sent on concurrently
+    pub connections: usize,
+    /// number of worker threads the connections are spread across
+    pub threads: usize,
+    /// cores to pin the worker threads to, round robin (empty to leave them be)
+    pub cores: Vec<usize>,
+    /// core to pin the histogram writer thread to
+    pub hist_core: Option<usize>,
+    /// number of requests each connection keeps in flight (pipelining, if more than 1)
+    pub pipeline_depth: usize,
+    /// open a new connection for each request (`Connection: close`), recording the
+    /// time taken to connect and to handshake
+    pub connection_per_request: bool,
+    /// send requests for this long before recording any, discarding their samples
+    pub warmup: Option<Duration>,
+    /// stop sending requests this long after the warmup
+    pub duration: Option<Duration>,
+    /// stop after sending this many requests (after the warmup), once they are
+    /// answered
+    pub requests: Option<u64>,
+}
+
+/// The client's command line defaults.
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            throttle: None,
+            rate: None,
+            padding: 0,
+            hist_dir: "var/hist/".to_string(),
+            interval: Duration::from_secs(30),
+            calibrate: 0,
+            apply_offset: false,
+            connections: 1,
+            threads: 1,
+            cores: Vec::new(),
+            hist_core: None,
+            pipeline_depth: 1,
+            connection_per_request: false,
+            warmup: None,
+            duration: None,
+            requests: None,
+        }
+    }
+}
+
+/// A transport that can be driven by the shared request loop (`run`).
+///
+/// Implementors provide connection setup and, where needed, request encoding and
+/// response framing; the loop takes care of sending, timing, recording and the
+/// stop/throttle logic. A client is shared by the worker threads of a run.
+pub trait BenchClient: Send + Sync + 'static {
+    type Stream: Read + Write;
+
+    /// Series name of the round-trip histogram log file.
+    fn series(&self) -> &'static str;
+
+    /// Code sent in the request body identifying the client type (see `client_tag`).
+    fn client_code(&self) -> u16;
+
+    /// Tag of the round-trip histograms: the `client_tag` of `client_code`, unless
+    /// the client's configuration calls for a more specific one.
+    fn tag(&self) -> &'static str {
+        crate::client_tag(self.client_code()).unwrap()
+    }
+
+    /// Value of the request's `Host` header.
+    fn host(&self) -> String;
+
+    /// Open a tcp connection to the server (see `connect_tcp`).
+    fn connect(&self, logger: &Logger) -> io::Result<TcpStream>;
+
+    /// Finish establishing the connection, e.g. perform a TLS handshake.
+    fn handshake(&self, stream: TcpStream, logger: &Logger) -> io::Result<Self::Stream>;
+
+    /// Request line and headers, except `Content-Length`, which is appended along
+    /// with the body (see `wire::Body`) for each request. Asks the server to close
+    /// the connection after responding unless `keep_alive`.
+    fn request_head(&self, keep_alive: bool) -> String {
+        format!(
             "POST /{path}/ HTTP/1.1\r\n\
              Host: {host}\r\n\
              User-Agent: rust-http-benchmarks-client/v{version}\r\n\
-             Connection: keep-alive\r\n\
-             Content-Length: 22\r\n\r\n\
-             {client_code} ",
+             Connection: {connection}\r\n",
              path = crate::REQUEST_PATH,
-             host = addr,
+             host = self.host(),
              version = crate_version!(),
-             client_code = client_code);
-        let n = headers.as_bytes().len();
-        info!(logger, "assembled request headers"; "headers" => format!("\n{}\n", headers), "n" => n, "ip" => %addr.ip());
-        //snd[..n].copy_from_slice(&b"POST / HTTP/1.1\r\nHost: bench.mmcxi.com\r\nConnection: keep-alive\r\nContent-Length: 22\r\n\r\n11 "[..]);
-        snd[..n].copy_from_slice(headers.as_bytes());
-        assert!(jetscii::ByteSubstring::new(b"\r\n\r\n").find(&snd[..n]).is_some());
-
-        'a: while !stop.load(Ordering::Relaxed) {
-            loop_time = Instant::now();
-            //snd[(length-2)..length].copy_from_slice(&b"\r\n"[..]);
-            TcpStream::connect(&addr).map_err(|e| {
-                error!(logger, "failed to connect: {:?}", e);
-                warn!(logger, "sleeping 1s on connection error before retry");
-                thread::sleep(Duration::from_secs(1));
-                e
-            }).map(|mut stream| {
-                stream.set_nonblocking(true).expect("send nonblocking");
-                stream.set_nodelay(true).expect("send nodelay");
-                trace!(logger, "stream: connected");
-                'b: while !stop.load(Ordering::Relaxed) {
-                    length = n + 4 + itoa::write(&mut snd[n..], crate::nanos(Utc::now())).unwrap();
-                    snd[(length-4)..length].copy_from_slice(&b"\r\n\r\n"[..]);
-                    debug!(logger, "sending request:\n{}", unsafe { str::from_utf8_unchecked(&snd[..length]) });
-                    let mut bytes_sent = 0;
-                    'c: loop {
-                        match stream.write(&snd[bytes_sent..length]) {
-                            Ok(n) => {
-                                bytes_sent += n;
-                            }
+             connection = if keep_alive { "keep-alive" } else { "close" })
+    }
 
-                            Err(e) => {
-                                trace!(logger, "stream.write err: {:?}", e);
+    /// Parses the response at the start of `buf`, returning `None` until it is
+    /// complete; `eof` is whether the server has closed the connection (see
+    /// `response::parse`).
+    fn parse_response(&self, buf: &[u8], eof: bool) -> Result<Option<Response>, response::Error> {
+        response::parse(buf, eof)
+    }
+}
 
-                                #[cfg(any(feature = "trace", feature = "debug"))]
-                                thread::sleep(Duration::from_millis(100));
-                            }
-                        }
+/// Connects to `addr` and configures the socket the way the request loop expects
+/// (nonblocking, `TCP_NODELAY`).
+pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nonblocking(true)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
 
-                        if bytes_sent >= length { break 'c }
+/// Plain tcp, no TLS (use stunnel bridge if TLS desired).
+pub struct RawTcp {
+    addr: SocketAddr,
+}
 
-                        if stop.load(Ordering::Relaxed) { break 'b }
-                    }
+impl RawTcp {
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no socket address")
+        })?;
+        Ok(Self { addr })
+    }
+}
 
-                    n_sent_stream += 1;
+impl BenchClient for RawTcp {
+    type Stream = TcpStream;
 
-                    //stream.shutdown(Shutdown::Write).expect("shutdown write");
-                    trace!(logger, "awaiting resp");
-                    let mut bytes_rcvd = 0;
-                    'd: loop {
-                        match stream.read(&mut rcv[bytes_rcvd..]) {
-                            Ok(n) => {
-                                bytes_rcvd += n;
-                                trace!(logger, "{} bytes rcvd: {}", bytes_rcvd, str::from_utf8(&rcv[..bytes_rcvd]).unwrap());
-                            }
+    fn series(&self) -> &'static str { "raw_tcp_client" }
 
-                            Err(e) => {
-                                #[cfg(feature = "trace")]
-                                {
-                                    if e.kind() != io::ErrorKind::WouldBlock {
-                                        trace!(logger, "stream.read err: {:?}", e);
-                                    }
-                                }
-                            }
-                        }
+    fn client_code(&self) -> u16 { 11 }
 
-                        if stop.load(Ordering::Relaxed) {
-                            break 'b
-                        }
+    fn host(&self) -> String { self.addr.to_string() }
 
-                        //if &rcv[..bytes_rcvd] == HTTP_204 { break 'c }
-                        if let Some(i) = jetscii::ByteSubstring::new(b"\r\n\r\n").find(&rcv[..bytes_rcvd]) {
-                            trace!(logger, "rcvd resp:\n {}", unsafe { str::from_utf8_unchecked(&rcv[..i]) });
-                            n_sent += 1;
-                            break 'd
-                        }
-                    }
+    fn connect(&self, _: &Logger) -> io::Result<TcpStream> { connect_tcp(self.addr) }
 
-                    if n_sent % crate::HEARTBEAT_EVERY == 0 {
-                        info!(logger, "sent {} requests in {:?}", n_sent.thousands_sep(), Instant::now() - start);
-                    }
+    fn handshake(&self, stream: TcpStream, _: &Logger) -> io::Result<TcpStream> { Ok(stream) }
+}
 
-                    if let &Some(throttle) = &throttle {
-                        thread::sleep(throttle);
-                    }
+/// TLS parameters of the openssl and h2 clients.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// cipher list for TLS 1.2 and below, in openssl's format (e.g. "AES128-SHA")
+    pub ciphers: Option<String>,
+    pub min_version: Option<SslVersion>,
+    pub max_version: Option<SslVersion>,
+    /// resume the previous connection's session when reconnecting
+    pub resume: bool,
+    /// PEM file of the certificate authorities to verify the server with, in addition
+    /// to the default ones
+    pub ca_file: Option<String>,
+    /// skip verifying the server's certificate
+    pub insecure: bool,
+    /// name sent as SNI and verified against the server's certificate, instead of the
+    /// uri's host
+    pub servername: Option<String>,
+}
 
-                    if cfg!(any(feature = "trace", feature = "debug")) {
-                        thread::sleep(Duration::from_secs(1));
-                    }
+/// Parses a TLS version as given on the command line ("1.0" through "1.3").
+pub fn parse_tls_version(s: &str) -> Option<SslVersion> {
+    match s {
+        "1.0" => Some(SslVersion::TLS1),
+        "1.1" => Some(SslVersion::TLS1_1),
+        "1.2" => Some(SslVersion::TLS1_2),
+        "1.3" => Some(SslVersion::TLS1_3),
+        _ => None
+    }
+}
+
+fn tls_version_str(version: SslVersion) -> &'static str {
+    match version {
+        SslVersion::TLS1 => "1.0",
+        SslVersion::TLS1_1 => "1.1",
+        SslVersion::TLS1_2 => "1.2",
+        SslVersion::TLS1_3 => "1.3",
+        _ => "?",
+    }
+}
+
+impl TlsOptions {
+    fn connector(&self) -> Result<openssl::ssl::SslConnectorBuilder, openssl::error::ErrorStack> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if let Some(ref ciphers) = self.ciphers {
+            builder.set_cipher_list(ciphers)?;
+        }
+        builder.set_min_proto_version(self.min_version)?;
+        builder.set_max_proto_version(self.max_version)?;
+        if let Some(ref ca_file) = self.ca_file {
+            builder.set_ca_file(ca_file)?;
+        }
+        if self.insecure {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+        Ok(builder)
+    }
+
+    /// Describes the settings that change what is being measured, to keep results
+    /// separate in the tag: `{ciphers=AES128-SHA;max=1.2;resume}`. `None` for the
+    /// defaults.
+    pub fn tag_suffix(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(ref ciphers) = self.ciphers { parts.push(format!("ciphers={}", ciphers)) }
+        if let Some(version) = self.min_version { parts.push(format!("min={}", tls_version_str(version))) }
+        if let Some(version) = self.max_version { parts.push(format!("max={}", tls_version_str(version))) }
+        if self.resume { parts.push("resume".to_string()) }
+        if self.insecure { parts.push("insecure".to_string()) }
+        if parts.is_empty() { None } else { Some(format!("{{{}}}", parts.join(";"))) }
+    }
+}
+
+/// Tcp+tls using rust bindings to openssl.
+pub struct OpensslTcp {
+    addr: http::Uri,
+    connector: SslConnector,
+    tls: TlsOptions,
+    tag: &'static str,
+    /// latest session, resumed by the next connection if `tls.resume`
+    session: Arc<Mutex<Option<SslSession>>>,
+}
+
+impl OpensslTcp {
+    pub fn new(addr: http::Uri, tls: TlsOptions) -> Result<Self, openssl::error::ErrorStack> {
+        let session: Arc<Mutex<Option<SslSession>>> = Default::default();
+        let mut connector = tls.connector()?;
+        if tls.resume {
+            // called once the session is established, which for TLS 1.3 is after the
+            // handshake, when the server's session ticket arrives
+            let latest = Arc::clone(&session);
+            connector.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+            connector.set_new_session_callback(move |_, session| {
+                *latest.lock().unwrap() = Some(session);
+            });
+        }
+        let connector = connector.build();
+        let base = crate::client_tag(12).unwrap();
+        let tag = match tls.tag_suffix() {
+            Some(suffix) => crate::leak_tag(format!("{}{}", base, suffix)),
+            None => base,
+        };
+        Ok(Self { addr, connector, tls, tag, session })
+    }
+}
+
+impl BenchClient for OpensslTcp {
+    type Stream = SslStream<TcpStream>;
+
+    fn series(&self) -> &'static str { "openssl_client" }
 
-                    // if server resp does not include text "keep-alive", return
-                    // from closure with active `stream`, triggering the creation of
-                    // a new connection on the next iteration of `'a` loop.
-                    //
-                    // this is a quick and dirty check for the server approving the keep-alive
-                    // with a 
-                    //
-                    if jetscii::ByteSubstring::new(b"keep-alive").find(&rcv[..bytes_rcvd]).is_none() { return () }
+    fn client_code(&self) -> u16 { 12 }
+
+    fn tag(&self) -> &'static str { self.tag }
+
+    fn host(&self) -> String { self.addr.host().unwrap().to_string() }
+
+    fn connect(&self, _: &Logger) -> io::Result<TcpStream> {
+        connect_tcp(self.addr.authority_part().unwrap().as_str())
+    }
+
+    fn handshake(&self, stream: TcpStream, logger: &Logger) -> io::Result<SslStream<TcpStream>> {
+        trace!(logger, "stream: connected, initializing tls...");
+        let err = |e| io::Error::other(format!("tls handshake failed: {:?}", e));
+        let setup_err = |e| io::Error::other(format!("tls setup failed: {:?}", e));
+        let mut config = self.connector.configure().map_err(setup_err)?;
+        if self.tls.insecure {
+            config.set_verify_hostname(false);
+        }
+        if self.tls.resume {
+            if let Some(ref session) = *self.session.lock().unwrap() {
+                // safe: the session was negotiated with the same `SslContext`
+                unsafe { config.set_session(session).map_err(setup_err)?; }
+            }
+        }
+        let domain = self.tls.servername.as_deref().unwrap_or_else(|| self.addr.host().unwrap());
+        let stream = match config.connect(domain, stream) {
+            Ok(stream) => stream,
+
+            Err(HandshakeError::WouldBlock(mut handshake)) => {
+                loop {
+                    handshake = match handshake.handshake() {
+                        Ok(stream) => break stream,
+                        Err(HandshakeError::WouldBlock(handshake)) => handshake,
+                        Err(e) => return Err(err(e)),
+                    };
+                }
+            }
+
+            Err(e) => return Err(err(e)),
+        };
+        trace!(logger, "tls connected";
+               "version" => stream.ssl().version_str(),
+               "cipher" => stream.ssl().current_cipher().map(|c| c.name()).unwrap_or("none"),
+               "resumed" => stream.ssl().session_reused());
+        Ok(stream)
+    }
+}
+
+/// Exchanges `probes` timestamped requests with the server's calibration endpoint
+/// (see `wire::CALIBRATION_PATH`) and fits the clock offset and drift to them.
+///
+/// Returns `None` if calibration was interrupted by `stop` or the server did not
+/// answer with timestamps.
+pub fn calibrate<C: BenchClient>(client: &C, probes: usize, stop: &AtomicBool, logger: &Logger) -> Option<Calibration> {
+    let req = format!(
+        "GET {path} HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         User-Agent: rust-http-benchmarks-client/v{version}\r\n\
+         Connection: keep-alive\r\n\r\n",
+         path = wire::CALIBRATION_PATH,
+         host = client.host(),
+         version = crate_version!());
+    let mut rcv = [0u8; 512];
+    let mut exchanges = Vec::with_capacity(probes);
+    let mut stream: Option<C::Stream> = None;
+    let mut failures = 0;
+
+    while exchanges.len() < probes && !stop.load(Ordering::Relaxed) {
+        if failures >= 10 {
+            warn!(logger, "giving up on clock calibration"; "failures" => failures);
+            return None
+        }
+        let mut conn = match stream.take() {
+            Some(conn) => conn,
+            None => match client.connect(logger).and_then(|conn| client.handshake(conn, logger)) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!(logger, "calibration failed to connect: {:?}", e);
+                    failures += 1;
+                    thread::sleep(Duration::from_secs(1));
+                    continue
+                }
+            }
+        };
+
+        let t1 = crate::nanos(Utc::now()) as i64;
+        let mut bytes_sent = 0;
+        let mut bytes_rcvd = 0;
+        let resp = loop {
+            if stop.load(Ordering::Relaxed) { return None }
+            let sending = bytes_sent < req.len();
+            let result = if sending {
+                conn.write(&req.as_bytes()[bytes_sent..])
+            } else {
+                conn.read(&mut rcv[bytes_rcvd..])
+            };
+            match result {
+                Ok(0) if !sending => {
+                    warn!(logger, "calibration connection closed by server, reconnecting");
+                    break None
+                }
+                Ok(n) if sending => bytes_sent += n,
+                Ok(n) => bytes_rcvd += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    warn!(logger, "calibration request failed, reconnecting: {:?}", e);
+                    break None
+                }
+            }
+            match client.parse_response(&rcv[..bytes_rcvd], false) {
+                Ok(Some(resp)) => break Some(resp),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(logger, "invalid calibration response, reconnecting: {}", e);
+                    break None
                 }
-            }).ok();
+            }
+            if bytes_rcvd == rcv.len() {
+                warn!(logger, "calibration response exceeds receive buffer");
+                break None
+            }
+        };
+        let t4 = crate::nanos(Utc::now()) as i64;
 
-            #[cfg(any(feature = "trace", feature = "debug"))]
-            thread::sleep(Duration::from_secs(1));
+        let resp = match resp {
+            Some(resp) => resp,
+            None => {
+                failures += 1;
+                continue
+            }
+        };
+        match (resp.recv_nanos, resp.send_nanos) {
+            (Some(t2), Some(t3)) => exchanges.push(Exchange { t1, t2: t2 as i64, t3: t3 as i64, t4 }),
+            _ => {
+                warn!(logger, "server does not support clock calibration";
+                      "resp" => String::from_utf8_lossy(&rcv[..resp.len]).into_owned());
+                return None
+            }
         }
-        n_sent
-    })
+        if resp.keep_alive {
+            stream = Some(conn);
+        }
+        thread::sleep(CALIBRATION_SPACING);
+    }
+
+    if exchanges.len() < probes { return None }
+    Calibration::fit(&exchanges, CALIBRATION_BUCKETS)
+}
+
+/// Saves `calibration` next to the interval log of `rtt`, returning the path of
+/// the file.
+fn write_calibration(rtt: &HistLog, host: &str, calibration: &Calibration, applied: bool) -> io::Result<PathBuf> {
+    #[derive(Serialize)]
+    struct Json<'a> {
+        host: &'a str,
+        applied: bool,
+        calibration: &'a Calibration,
+    }
+    let path = crate::sidecar_path(rtt.path(), "clock-calibration.json")?;
+    let mut json = serde_json::to_vec(&Json { host, applied, calibration })?;
+    json.push(b'\n');
+    fs::write(&path, json)?;
+    Ok(path)
+}
+
+/// Creates the round-trip log of a client, on a thread pinned to `settings.hist_core`
+/// if given, so that the log's writer thread is pinned there too.
+fn rtt_log(settings: &Settings, series: &'static str, tag: &'static str, logger: &Logger) -> (HistLog, Vec<Pinned>) {
+    let (hist_dir, interval) = (settings.hist_dir.clone(), settings.interval);
+    let new = move || HistLog::new(&hist_dir, series, tag, interval).unwrap();
+    match settings.hist_core {
+        Some(core) => {
+            let (rtt, pinned) = affinity::spawn_pinned("histlog", core, logger, new);
+            (rtt, vec![pinned])
+        }
+        None => (new(), Vec::new()),
+    }
+}
+
+/// Reads the interval log at `log` back into a latency summary, once its last
+/// `HistLog` is dropped, logging the percentile table.
+fn read_report(log: &Path, logger: &Logger) -> Option<Report> {
+    match Report::read(log) {
+        Ok(report) => {
+            info!(logger, "latency summary\n{}", report);
+            Some(report)
+        }
+        Err(e) => {
+            error!(logger, "failed to read back the interval log: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Saves the run metadata of a client next to `rtt`, including the pinning applied.
+fn save_meta(rtt: &HistLog, pinned: &[Pinned], logger: &Logger) {
+    match crate::write_meta(rtt.path(), pinned) {
+        Ok(path) => info!(logger, "saved run metadata"; "path" => path.display().to_string()),
+        Err(e) => error!(logger, "failed to save run metadata: {:?}", e),
+    }
+}
+
+/// Fixed timeline of intended send times used by the constant-rate (open loop) mode.
+///
+/// Latency is measured from the intended send time rather than the actual one, so
+/// requests that should have gone out while the client was stuck waiting on a slow
+/// response are still accounted for (coordinated omission correction, as in wrk2).
+struct Schedule {
+    start: Instant,
+    rate: u64,
+    /// slot of the next request in the combined timeline
+    slot: u64,
+    /// slots between two requests of this connection
+    stride: u64,
+}
+
+impl Schedule {
+    /// Timeline of connection `i` of `n` sending `rate` requests per second between
+    /// them: every `n`th slot of the combined timeline beginning at `start`,
+    /// starting with slot `i`.
+    fn new(rate: NonZeroU64, start: Instant, n: usize, i: usize) -> Self {
+        Self { start, rate: rate.get(), slot: i as u64, stride: n as u64 }
+    }
+
+    /// Returns the intended send time of the next request and advances the timeline.
+    fn next(&mut self) -> Instant {
+        // from the slot number rather than by adding up a period, which would round
+        // to 0 above 1e9 requests per second
+        let nanos = u128::from(self.slot) * 1_000_000_000 / u128::from(self.rate);
+        self.slot += self.stride;
+        self.start + Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// Histograms recorded only in constant-rate mode, alongside the round-trip log.
+struct OpenLoop {
+    /// latency measured from the intended send time
+    corrected: HistLog,
+    /// gap between the intended and actual send time
+    send_gap: HistLog,
+}
+
+impl OpenLoop {
+    fn new(rtt: &HistLog, tag: &str) -> Self {
+        Self {
+            corrected: rtt.clone_with_tag(crate::leak_tag(format!("{}:corrected", tag))),
+            send_gap: rtt.clone_with_tag(crate::leak_tag(format!("{}:send-gap", tag))),
+        }
+    }
+
+    fn record(&mut self, intended: Instant, write_start: Instant, rcvd: Instant) {
+        self.corrected.record(histlog::nanos(rcvd - intended)).ok();
+        self.send_gap.record(histlog::nanos(write_start - intended)).ok();
+        self.corrected.check_send(rcvd);
+        self.send_gap.check_send(rcvd);
+    }
+}
+
+/// Histograms recorded only in connection-per-request mode, alongside the
+/// round-trip log.
+struct Setup {
+    /// time taken to open the tcp connection
+    connect: HistLog,
+    /// time taken by `BenchClient::handshake`, e.g. the TLS handshake
+    handshake: HistLog,
+}
+
+impl Setup {
+    fn new(rtt: &HistLog, tag: &str) -> Self {
+        Self {
+            connect: rtt.clone_with_tag(crate::leak_tag(format!("{}:connect", tag))),
+            handshake: rtt.clone_with_tag(crate::leak_tag(format!("{}:handshake", tag))),
+        }
+    }
+
+    fn record(&mut self, start: Instant, connected: Instant, handshaken: Instant) {
+        self.connect.record(histlog::nanos(connected - start)).ok();
+        self.handshake.record(histlog::nanos(handshaken - connected)).ok();
+        self.connect.check_send(handshaken);
+        self.handshake.check_send(handshaken);
+    }
+}
+
+/// When a client run stops sending requests, short of `stop` being set, and which
+/// of its samples are discarded as warmup. Shared by the worker threads of a run.
+struct Limits {
+    /// end of the warmup: requests written before it are not recorded
+    warm: Instant,
+    /// when to stop sending, if `Settings::duration`
+    end: Option<Instant>,
+    /// requests left to send after the warmup, if `Settings::requests`
+    left: Option<AtomicU64>,
+}
+
+impl Limits {
+    fn new(settings: &Settings, start: Instant) -> Self {
+        let warm = start + settings.warmup.unwrap_or_default();
+        Self {
+            warm,
+            end: settings.duration.map(|duration| warm + duration),
+            left: settings.requests.map(AtomicU64::new),
+        }
+    }
+
+    /// Takes a request to send at `now`, returning `false` if the run has
+    /// sent all it is allowed to.
+    fn take(&self, now: Instant) -> bool {
+        if self.end.is_some_and(|end| now >= end) { return false }
+        if self.is_warmup(now) { return true }
+        match self.left {
+            Some(ref left) => left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Gives back a request taken at `t` that was dropped unanswered, to be sent
+    /// again.
+    fn give_back(&self, t: Instant) {
+        if self.is_warmup(t) { return }
+        if let Some(ref left) = self.left {
+            left.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether no more requests will be sent after `now`.
+    fn exhausted(&self, now: Instant) -> bool {
+        self.end.is_some_and(|end| now >= end)
+            || self.left.as_ref().is_some_and(|left| left.load(Ordering::Relaxed) == 0)
+    }
+
+    fn is_warmup(&self, t: Instant) -> bool {
+        t < self.warm
+    }
+}
+
+pub fn raw_tcp_client<A: ToSocketAddrs>(
+    addr: A,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Outcome> {
+    let client = RawTcp::new(addr).unwrap();
+    let logger = logger.new(o!("thread" => "raw tcp", "addr" => format!("{}", client.addr)));
+    run(client, stop, settings, &logger)
 }
 
 pub fn openssl_client(
     addr: http::Uri,
-    // core: usize,
-    // topo: Arc<Mutex<hwloc::Topology>>,
+    tls: TlsOptions,
     stop: Arc<AtomicBool>,
-    throttle: Option<Duration>,
+    settings: &Settings,
     logger: &Logger,
-) -> thread::JoinHandle<usize> {
+) -> thread::JoinHandle<Outcome> {
     let logger = logger.new(o!(
         "thread" => "client[raw tcp+tls[openssl]]",
         "addr" => addr.to_string(),
         "host" => addr.host().unwrap().to_string(),
     ));
+    let client = OpensslTcp::new(addr, tls)
+        .map_err(|e| {
+            error!(logger, "failed to build SslConnector: {:?}", e);
+        }).expect("SslConnector::builder(SslMethod::tls())");
+    run(client, stop, settings, &logger)
+}
+
+/// Spawns a thread sending requests with `client` until `stop` is set, or the run's
+/// `settings.duration` or `settings.requests` is up, returning the responses
+/// received (after the warmup) and the latency summary of the run.
+///
+/// Requests are sent on `settings.connections` connections, spread round robin
+/// across `settings.threads` worker threads, with up to `settings.pipeline_depth`
+/// in flight on each. Each connection is reused for as long as the server keeps it
+/// alive, unless `settings.connection_per_request`. The round-trip time of each
+/// request, from the start of the write until the end of the response is received,
+/// is recorded to an interval log in `settings.hist_dir`, if the response is a 2xx.
+pub fn run<C: BenchClient>(
+    client: C,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Outcome> {
+    let logger = logger.clone();
+    // round trips on fresh or pipelined connections are a different measurement:
+    // keep them apart
+    let tag = match settings.pipeline_depth {
+        _ if settings.connection_per_request => crate::leak_tag(format!("{}{{connection=close}}", client.tag())),
+        0 | 1 => client.tag(),
+        depth => crate::leak_tag(format!("{}{{pipeline={}}}", client.tag(), depth)),
+    };
+    if settings.connection_per_request && settings.pipeline_depth > 1 {
+        warn!(logger, "--pipeline-depth is ignored with --connection-per-request");
+    }
+    let (rtt, mut pinned) = rtt_log(settings, client.series(), tag, &logger);
+    let settings = settings.clone();
     thread::spawn(move || {
-        // #[cfg(feature = "affinity")]
-        // bind_thread(topo, core, &logger);
+        let calibration = if settings.calibrate > 0 {
+            info!(logger, "calibrating clock offset"; "probes" => settings.calibrate);
+            calibrate(&client, settings.calibrate, &stop, &logger)
+        } else {
+            None
+        };
+        if let Some(ref calibration) = calibration {
+            info!(logger, "clock calibration";
+                  "offset_nanos" => calibration.offset,
+                  "drift_ppb" => calibration.drift_ppb(),
+                  "min_delay_nanos" => calibration.min_delay);
+            match write_calibration(&rtt, &client.host(), calibration, settings.apply_offset) {
+                Ok(path) => info!(logger, "saved clock calibration"; "path" => path.display().to_string()),
+                Err(e) => error!(logger, "failed to save clock calibration: {:?}", e),
+            }
+        }
+        let offset = calibration.filter(|_| settings.apply_offset);
+
+        let headers = client.request_head(!settings.connection_per_request);
+        info!(logger, "assembled request headers"; "headers" => format!("\n{}\n", headers), "n" => headers.len());
+        assert!(headers.ends_with("\r\n"));
+
+        let connections = settings.connections.max(1);
+        let threads = settings.threads.max(1).min(connections);
+        info!(logger, "starting workers"; "connections" => connections, "threads" => threads);
+        let client = Arc::new(client);
         let start = Instant::now();
-        let client_code = 12;
-        let mut loop_time: Instant;
-        let mut length: usize = 89;
-        let mut n_sent = 0;
-        let mut n_sent_stream = 0;
-        let mut snd = [0u8; 512];
-        let mut rcv = [0u8; 512];
-
-        let headers = format!(
-            "POST /{path}/ HTTP/1.1\r\n\
-             Host: {host}\r\n\
-             User-Agent: rust-http-benchmarks-client/v{version}\r\n\
-             Connection: keep-alive\r\n\
-             Content-Length: 22\r\n\r\n\
-             {client_code} ",
-             path = crate::REQUEST_PATH,
-             host = addr.host().unwrap(),
-             version = crate_version!(),
-             client_code = client_code);
-        let n = headers.as_bytes().len();
-        info!(logger, "assembled request headers"; "headers" => format!("\n{}\n", headers), "n" => n);
-        snd[..n].copy_from_slice(headers.as_bytes());
-        assert!(jetscii::ByteSubstring::new(b"\r\n\r\n").find(&snd[..n]).is_some());
-
-        let connector = SslConnector::builder(SslMethod::tls())
-            .map_err(|e| {
-                error!(logger, "failed to build SslConnector: {:?}", e);
-            }).expect("SslConnector::builder(SslMethod::tls())").build();
-
-        'a: while !stop.load(Ordering::Relaxed) {
-            loop_time = Instant::now();
-            //snd[(length-2)..length].copy_from_slice(&b"\r\n"[..]);
-            let conn: &str = addr.authority_part().unwrap().as_str();
-            TcpStream::connect(conn).map_err(|e| {
-                error!(logger, "failed to connect: {:?}", e; "addr" => %conn, "uri" => %addr);
-                warn!(logger, "waiting 1s until retry on failed connection attempt");
-                thread::sleep(Duration::from_secs(1));
-            }).and_then(|mut stream| {
-                stream.set_nonblocking(true).expect("send nonblocking");
-                stream.set_nodelay(true).expect("send nodelay");
+        let limits = Arc::new(Limits::new(&settings, start));
+        if let Some(warmup) = settings.warmup {
+            info!(logger, "warming up"; "warmup" => format!("{:?}", warmup));
+        }
+        let (pin_tx, pin_rx) = mpsc::channel();
+        let workers: Vec<_> = (0..threads).map(|w| {
+            let conns: Vec<(usize, Option<Schedule>)> = (w..connections).step_by(threads)
+                .map(|i| (i, settings.rate.map(|rate| Schedule::new(rate, start, connections, i))))
+                .collect();
+            let core = if settings.cores.is_empty() { None } else { Some(settings.cores[w % settings.cores.len()]) };
+            let worker = Worker::new(Arc::clone(&client), &rtt, tag, offset.clone(), Arc::clone(&limits), &settings,
+                                     logger.new(o!("worker" => w)));
+            let stop = Arc::clone(&stop);
+            let pin_tx = pin_tx.clone();
+            thread::Builder::new().name(format!("client-worker-{}", w)).spawn(move || {
+                let pinned = core.map(|core| affinity::pin(&format!("worker-{}", w), core, &worker.logger));
+                pin_tx.send((w, pinned)).ok();
+                drop(pin_tx);
+                worker.run(conns, &stop)
+            }).unwrap()
+        }).collect();
 
-                trace!(logger, "stream: connected, initializing tls...");
+        // ends once every worker has sent (or panicked before sending)
+        drop(pin_tx);
+        let mut workers_pinned: Vec<(usize, Option<Pinned>)> = pin_rx.iter().collect();
+        if workers_pinned.len() < threads {
+            warn!(logger, "workers exited before pinning"; "missing" => threads - workers_pinned.len());
+        }
+        workers_pinned.sort_by_key(|&(w, _)| w);
+        pinned.extend(workers_pinned.into_iter().filter_map(|(_, pinned)| pinned));
+        save_meta(&rtt, &pinned, &logger);
 
-                match connector.connect("bench.mmcxi.com", stream) {
-                    Ok(stream) => Ok(stream),
-
-                    Err(HandshakeError::WouldBlock(mut handshake)) => {
-                        loop {
-                            handshake = match handshake.handshake() {
-                                Ok(stream) => return Ok(stream),
-                                Err(HandshakeError::WouldBlock(handshake)) => handshake,
-                                Err(e) => {
-                                    error!(logger, "error calling connector.connect: {:?}", e);
-                                    return Err(())
-                                }
-                            };
-                        }
+        let mut responses = Responses::default();
+        for worker in workers {
+            responses += worker.join().unwrap();
+        }
+        info!(logger, "client finished"; "responses" => %responses, "elapsed" => format!("{:?}", Instant::now() - start));
+        let log = rtt.path().to_path_buf();
+        drop(rtt);
+        Outcome { responses, report: read_report(&log, &logger) }
+    })
+}
+
+/// Timing of a request a connection has sent (or is sending), awaiting its response.
+#[derive(Clone, Copy)]
+struct InFlight {
+    /// intended send time (constant-rate mode)
+    intended: Option<Instant>,
+    write_start: Instant,
+    /// `sent` field of the request body
+    sent: u64,
+}
+
+/// One of the connections of a worker thread, keeping up to `Worker::depth`
+/// requests in flight.
+///
+/// Each is a client run of its own as far as the server is concerned (see
+/// `wire::conn_id`), with its own sequence numbers across reconnects.
+struct Conn<S> {
+    /// `None` until connected, and after the connection is lost
+    stream: Option<S>,
+    /// when to (re)connect, while `stream` is `None`
+    connect_at: Instant,
+    /// when the next request may be sent: the intended send time in constant-rate
+    /// mode, otherwise the end of the throttle
+    send_at: Instant,
+    /// intended send times, in constant-rate mode
+    schedule: Option<Schedule>,
+    /// requests sent, oldest first; responses come back in the same order
+    in_flight: VecDeque<InFlight>,
+    run_id: u32,
+    /// number of connections made
+    conn: u32,
+    seq: u64,
+    /// requests not yet (fully) written, from `bytes_sent` on
+    snd: Vec<u8>,
+    bytes_sent: usize,
+    /// grows as needed, up to `MAX_RESPONSE_LEN`
+    rcv: Vec<u8>,
+    bytes_rcvd: usize,
+    logger: Logger,
+}
+
+impl<S> Conn<S> {
+    fn new(mut schedule: Option<Schedule>, capacity: usize, logger: Logger) -> Self {
+        let now = Instant::now();
+        Self {
+            stream: None,
+            connect_at: now,
+            send_at: schedule.as_mut().map(Schedule::next).unwrap_or(now),
+            schedule,
+            in_flight: VecDeque::new(),
+            run_id: wire::new_run_id(),
+            conn: 0,
+            seq: 0,
+            snd: Vec::with_capacity(capacity),
+            bytes_sent: 0,
+            rcv: vec![0u8; 512],
+            bytes_rcvd: 0,
+            logger,
+        }
+    }
+
+    /// When there is next something to do, unless waiting on the server.
+    fn wake(&self) -> Option<Instant> {
+        match self.stream {
+            None => Some(self.connect_at),
+            Some(_) if self.in_flight.is_empty() => Some(self.send_at),
+            Some(_) => None,
+        }
+    }
+
+    /// Drops the stream, along with the requests in flight on it, to connect again
+    /// at `at`. See `Worker::reconnect`.
+    fn reconnect(&mut self, at: Instant) {
+        self.stream = None;
+        self.connect_at = at;
+        self.in_flight.clear();
+        self.snd.clear();
+        self.bytes_sent = 0;
+        self.bytes_rcvd = 0;
+    }
+}
+
+/// A worker thread of `run`: the histograms and request settings shared by the
+/// connections it takes turns polling.
+struct Worker<C: BenchClient> {
+    client: Arc<C>,
+    rtt: HistLog,
+    open_loop: Option<OpenLoop>,
+    /// connection setup histograms, if opening a connection per request
+    setup: Option<Setup>,
+    split: Option<Split>,
+    tag: &'static str,
+    /// request line and headers, except `Content-Length`
+    head: String,
+    throttle: Option<Duration>,
+    padding: usize,
+    /// requests each connection keeps in flight
+    depth: usize,
+    /// calibrated clock offset applied to the `sent` timestamps
+    offset: Option<Calibration>,
+    limits: Arc<Limits>,
+    /// whether the histograms have been reset at the end of the warmup
+    warm: bool,
+    responses: Responses,
+    start: Instant,
+    logger: Logger,
+}
+
+impl<C: BenchClient> Worker<C> {
+    fn new(
+        client: Arc<C>,
+        rtt: &HistLog,
+        tag: &'static str,
+        offset: Option<Calibration>,
+        limits: Arc<Limits>,
+        settings: &Settings,
+        logger: Logger,
+    ) -> Self {
+        Self {
+            head: client.request_head(!settings.connection_per_request),
+            client,
+            rtt: rtt.clone(),
+            open_loop: settings.rate.map(|_| OpenLoop::new(rtt, tag)),
+            setup: if settings.connection_per_request { Some(Setup::new(rtt, tag)) } else { None },
+            split: None,
+            tag,
+            throttle: settings.throttle,
+            padding: settings.padding,
+            depth: if settings.connection_per_request { 1 } else { settings.pipeline_depth.max(1) },
+            offset,
+            warm: settings.warmup.is_none(),
+            limits,
+            responses: Responses::default(),
+            start: Instant::now(),
+            logger,
+        }
+    }
+
+    /// Sends requests on the connections numbered `conns` until `stop` is set, or
+    /// the run's limits are reached and every request sent has been answered,
+    /// returning the responses received.
+    fn run(mut self, conns: Vec<(usize, Option<Schedule>)>, stop: &AtomicBool) -> Responses {
+        // head, content-length header, body and padding of `depth` requests
+        let capacity = (self.head.len() + 128 + self.padding) * self.depth;
+        let mut conns: Vec<Conn<C::Stream>> = conns.into_iter()
+            .map(|(i, schedule)| Conn::new(schedule, capacity, self.logger.new(o!("conn" => i))))
+            .collect();
+
+        while !stop.load(Ordering::Relaxed) {
+            if self.limits.exhausted(Instant::now()) && conns.iter().all(|conn| conn.in_flight.is_empty()) {
+                break
+            }
+            for conn in conns.iter_mut() {
+                self.poll(conn);
+            }
+
+            // in closed-loop mode, sleep while every connection is waiting on the
+            // throttle or to reconnect, rather than spin; constant-rate mode spins
+            // to send on time
+            if self.open_loop.is_none() {
+                let wake = conns.iter().map(Conn::wake).collect::<Option<Vec<_>>>()
+                    .and_then(|wake| wake.into_iter().min());
+                if let Some(wake) = wake {
+                    let now = Instant::now();
+                    if wake > now { thread::sleep(wake - now) }
+                }
+            }
+        }
+
+        for conn in &conns {
+            info!(conn.logger, "connection finished"; "seq" => conn.seq, "connections" => conn.conn, "run_id" => conn.run_id);
+        }
+        info!(self.logger, "worker finished"; "responses" => %self.responses);
+        self.responses
+    }
+
+    /// Advances `conn` as far as it goes without waiting on the server: connects,
+    /// sends requests while fewer than `depth` are in flight, and reads the
+    /// responses that have arrived.
+    ///
+    /// Connecting blocks, holding up the worker's other connections until done.
+    fn poll(&mut self, conn: &mut Conn<C::Stream>) {
+        if conn.stream.is_none() {
+            let start = Instant::now();
+            if start < conn.connect_at || self.limits.exhausted(start) { return }
+            let mut connected = start;
+            let stream = self.client.connect(&conn.logger)
+                .and_then(|stream| {
+                    trace!(conn.logger, "stream: connected");
+                    connected = Instant::now();
+                    self.client.handshake(stream, &conn.logger)
+                });
+            match stream {
+                Ok(stream) => {
+                    if self.setup.is_some() && self.recording(start) {
+                        self.setup.as_mut().unwrap().record(start, connected, Instant::now());
                     }
+                    conn.conn += 1;
+                    conn.stream = Some(stream);
+                }
+                Err(e) => {
+                    error!(conn.logger, "failed to connect: {:?}", e);
+                    warn!(conn.logger, "waiting 1s on connection error before retry");
+                    conn.connect_at = Instant::now() + Duration::from_secs(1);
+                    return
+                }
+            }
+        }
+
+        while conn.in_flight.len() < self.depth {
+            // the same instant decides the budget and the warmup (see `record`)
+            let write_start = Instant::now();
+            if write_start < conn.send_at || !self.limits.take(write_start) { break }
+            // in constant-rate mode, `send_at` is the intended send time
+            let intended = conn.schedule.as_ref().map(|_| conn.send_at);
+            let sent = self.encode_request(conn);
+            conn.in_flight.push_back(InFlight { intended, write_start, sent });
+            if let Some(ref mut schedule) = conn.schedule {
+                conn.send_at = schedule.next();
+            }
+        }
+
+        let stream = conn.stream.as_mut().unwrap();
+        while conn.bytes_sent < conn.snd.len() {
+            match stream.write(&conn.snd[conn.bytes_sent..]) {
+                Ok(n) => {
+                    conn.bytes_sent += n;
+                }
+
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    #[cfg(any(feature = "trace", feature = "debug"))]
+                    thread::sleep(Duration::from_millis(100));
+                    break
+                }
+
+                Err(e) => {
+                    warn!(conn.logger, "stream.write err, reconnecting: {:?}", e);
+                    return self.reconnect(conn, Instant::now())
+                }
+            }
+        }
+        if conn.bytes_sent == conn.snd.len() {
+            conn.snd.clear();
+            conn.bytes_sent = 0;
+        }
+
+        while !conn.in_flight.is_empty() {
+            if conn.bytes_rcvd == conn.rcv.len() {
+                if conn.rcv.len() >= MAX_RESPONSE_LEN {
+                    error!(conn.logger, "response exceeds receive buffer, reconnecting"; "len" => conn.rcv.len());
+                    self.responses.invalid += 1;
+                    conn.in_flight.pop_front();
+                    return self.reconnect(conn, Instant::now())
+                }
+                let len = conn.rcv.len() * 2;
+                conn.rcv.resize(len, 0);
+            }
+
+            let eof = match stream.read(&mut conn.rcv[conn.bytes_rcvd..]) {
+                Ok(0) => true,
+
+                Ok(n) => {
+                    conn.bytes_rcvd += n;
+                    trace!(conn.logger, "{} bytes rcvd: {}", conn.bytes_rcvd, String::from_utf8_lossy(&conn.rcv[..conn.bytes_rcvd]));
+                    false
+                }
+
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+
+                Err(e) => {
+                    warn!(conn.logger, "stream.read err, reconnecting: {:?}", e);
+                    return self.reconnect(conn, Instant::now())
+                }
+            };
 
+            // responses come back in the order the requests were sent
+            loop {
+                let resp = match self.client.parse_response(&conn.rcv[..conn.bytes_rcvd], eof) {
+                    Ok(Some(resp)) => resp,
+                    Ok(None) => break,
                     Err(e) => {
-                        error!(logger, "error calling connector.connect: {:?}", e);
-                        Err(())
+                        error!(conn.logger, "invalid response, reconnecting: {}", e;
+                               "resp" => String::from_utf8_lossy(&conn.rcv[..conn.bytes_rcvd]).into_owned());
+                        self.responses.invalid += 1;
+                        conn.in_flight.pop_front();
+                        return self.reconnect(conn, Instant::now())
                     }
+                };
+                trace!(conn.logger, "rcvd resp:\n {}", String::from_utf8_lossy(&conn.rcv[..resp.len]));
+                if resp.is_interim() {
+                    conn.rcv.copy_within(resp.len..conn.bytes_rcvd, 0);
+                    conn.bytes_rcvd -= resp.len;
+                    continue
                 }
-            }).map(|mut stream| {
-                'b: while !stop.load(Ordering::Relaxed) {
-                    length = n + 4 + itoa::write(&mut snd[n..], crate::nanos(Utc::now())).unwrap();
-                    snd[(length-4)..length].copy_from_slice(&b"\r\n\r\n"[..]);
-                    trace!(logger, "sending request:\n{}", unsafe { str::from_utf8_unchecked(&snd[..length]) });
-                    let mut bytes_sent = 0;
-                    'c: loop {
-                        match stream.write(&snd[bytes_sent..length]) {
-                            Ok(n) => {
-                                bytes_sent += n;
-                            }
+                let req = match conn.in_flight.pop_front() {
+                    Some(req) => req,
+                    None => {
+                        error!(conn.logger, "response to no request, reconnecting";
+                               "resp" => String::from_utf8_lossy(&conn.rcv[..resp.len]).into_owned());
+                        return self.reconnect(conn, Instant::now())
+                    }
+                };
+                self.record(&resp, req);
 
-                            Err(e) => {
-                                trace!(logger, "stream.write err: {:?}", e);
-                            }
-                        }
+                if cfg!(any(feature = "trace", feature = "debug")) {
+                    thread::sleep(Duration::from_secs(1));
+                }
+
+                let resume = Instant::now() + self.throttle.unwrap_or_default();
+                if self.setup.is_some() {
+                    // connect in time for the next request, so its setup is
+                    // measured rather than done ahead of it
+                    let at = if conn.schedule.is_some() { conn.send_at } else { resume };
+                    return self.reconnect(conn, at)
+                }
+                if !resp.keep_alive {
+                    // the server did not approve the keep-alive: drop `stream`,
+                    // making a new connection
+                    return self.reconnect(conn, resume)
+                }
+                if conn.schedule.is_none() {
+                    conn.send_at = resume;
+                }
+                conn.rcv.copy_within(resp.len..conn.bytes_rcvd, 0);
+                conn.bytes_rcvd -= resp.len;
+            }
+
+            if eof {
+                warn!(conn.logger, "connection closed by server, reconnecting";
+                      "bytes_rcvd" => conn.bytes_rcvd);
+                return self.reconnect(conn, Instant::now())
+            }
+        }
+    }
+
+    /// Drops the stream of `conn`, along with the requests in flight on it, to
+    /// connect again at `at`. The requests dropped after the warmup are counted, and
+    /// given back to the run's `Settings::requests`.
+    fn reconnect(&mut self, conn: &mut Conn<C::Stream>, at: Instant) {
+        if !conn.in_flight.is_empty() {
+            warn!(conn.logger, "dropping requests in flight"; "n" => conn.in_flight.len());
+        }
+        for req in &conn.in_flight {
+            if self.limits.is_warmup(req.write_start) { continue }
+            self.responses.dropped += 1;
+            self.limits.give_back(req.write_start);
+        }
+        conn.reconnect(at)
+    }
+
+    /// Appends the next request of `conn` to its send buffer, returning the `sent`
+    /// timestamp of its body.
+    fn encode_request(&self, conn: &mut Conn<C::Stream>) -> u64 {
+        let now = crate::nanos(Utc::now());
+        let sent = match self.offset {
+            Some(ref offset) => (now as i64 + offset.offset_at(now as i64)).max(0) as u64,
+            None => now,
+        };
+        let body = wire::Body {
+            code: self.client.client_code(),
+            sent,
+            seq: conn.seq,
+            conn: wire::conn_id(conn.run_id, conn.conn),
+            padding: self.padding,
+        };
+        let start = conn.snd.len();
+        conn.snd.extend_from_slice(self.head.as_bytes());
+        conn.snd.extend_from_slice(b"Content-Length: ");
+        itoa::write(&mut conn.snd, body.encoded_len()).unwrap();
+        conn.snd.extend_from_slice(b"\r\n\r\n");
+        body.encode(&mut conn.snd).unwrap();
+        conn.seq += 1;
+        debug!(conn.logger, "sending request:\n{}", String::from_utf8_lossy(&conn.snd[start..]));
+        sent
+    }
+
+    /// Whether to record a sample starting at `t`: not during the warmup. The
+    /// histograms are reset at the first one after it, so that their first interval
+    /// starts there.
+    fn recording(&mut self, t: Instant) -> bool {
+        if self.limits.is_warmup(t) { return false }
+        if !self.warm {
+            self.warm = true;
+            self.rtt.reset();
+            if let Some(ref mut open_loop) = self.open_loop {
+                open_loop.corrected.reset();
+                open_loop.send_gap.reset();
+            }
+            if let Some(ref mut setup) = self.setup {
+                setup.connect.reset();
+                setup.handshake.reset();
+            }
+            if let Some(ref mut split) = self.split {
+                split.reset();
+            }
+        }
+        true
+    }
+
+    /// Records the round trip of `req`, given its response `resp`, unless it was
+    /// sent during the warmup.
+    fn record(&mut self, resp: &Response, req: InFlight) {
+        if !self.recording(req.write_start) { return }
+        let rcvd = Instant::now();
+        let t4 = crate::nanos(Utc::now()) as i64;
+        self.responses.count(resp.status);
+        if self.responses.total() % crate::HEARTBEAT_EVERY == 0 {
+            info!(self.logger, "sent {} requests in {:?}", self.responses.total().thousands_sep(), Instant::now() - self.start;
+                  "responses" => %self.responses);
+        }
+        if !resp.is_success() {
+            debug!(self.logger, "error response"; "status" => resp.status);
+            return
+        }
+        self.rtt.record(histlog::nanos(rcvd - req.write_start)).ok();
+        self.rtt.check_send(rcvd);
+        if let (Some(open_loop), Some(intended)) = (self.open_loop.as_mut(), req.intended) {
+            open_loop.record(intended, req.write_start, rcvd);
+        }
+        if let (Some(t2), Some(t3)) = (resp.recv_nanos, resp.send_nanos) {
+            let x = Exchange { t1: req.sent as i64, t2: t2 as i64, t3: t3 as i64, t4 };
+            let (rtt, tag) = (&self.rtt, self.tag);
+            let split = self.split.get_or_insert_with(|| Split::new(rtt, tag));
+            if split.record(&x, rcvd) {
+                info!(self.logger, "clock offset estimate"; "nanos" => split.offset.estimate());
+            }
+        }
+    }
+}
 
-                        if bytes_sent >= length { break 'c }
+/// HTTP/2 over tcp+tls using rust bindings to openssl, negotiated with ALPN.
+///
+/// Unlike the http/1.1 clients, which have one request in flight per connection,
+/// requests are sent on `streams` concurrent streams of a single connection.
+pub struct H2Tls {
+    addr: http::Uri,
+    connector: SslConnector,
+    tls: TlsOptions,
+    tag: &'static str,
+    streams: usize,
+}
+
+type H2Stream = tokio_openssl::SslStream<tokio::net::TcpStream>;
 
-                        if stop.load(Ordering::Relaxed) { break 'b }
+impl H2Tls {
+    /// `tls.resume` is not supported: the client reconnects only after errors.
+    pub fn new(addr: http::Uri, streams: usize, tls: TlsOptions) -> Result<Self, openssl::error::ErrorStack> {
+        let tls = TlsOptions { resume: false, ..tls };
+        let mut builder = tls.connector()?;
+        builder.set_alpn_protos(b"\x02h2")?;
+        let base = crate::client_tag(13).unwrap();
+        let tag = match tls.tag_suffix() {
+            Some(suffix) => crate::leak_tag(format!("{}{}", base, suffix)),
+            None => base,
+        };
+        Ok(Self { addr, connector: builder.build(), tls, tag, streams: streams.max(1) })
+    }
+
+    fn connect(&self, logger: &Logger) -> impl Future<Item = (h2::client::SendRequest<Bytes>, h2::client::Connection<H2Stream, Bytes>), Error = io::Error> {
+        let tls_err = |e| io::Error::other(format!("tls handshake failed: {:?}", e));
+        let h2_err = |e| io::Error::other(format!("h2 handshake failed: {:?}", e));
+        let addr = self.addr.authority_part().unwrap().as_str().to_socket_addrs()
+            .and_then(|mut addrs| {
+                addrs.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no socket address"))
+            });
+        let setup_err = |e| io::Error::other(format!("tls setup failed: {:?}", e));
+        let config = self.connector.configure().map_err(setup_err).map(|mut config| {
+            if self.tls.insecure {
+                config.set_verify_hostname(false);
+            }
+            config
+        });
+        let domain = self.tls.servername.as_deref().unwrap_or_else(|| self.addr.host().unwrap()).to_string();
+        let logger = logger.clone();
+        future::result(addr.and_then(|addr| config.map(|config| (addr, config))))
+            .and_then(|(addr, config)| tokio::net::TcpStream::connect(&addr).map(|stream| (stream, config)))
+            .and_then(|(stream, config)| stream.set_nodelay(true).map(|()| (stream, config)))
+            .and_then(move |(stream, config)| {
+                trace!(logger, "stream: connected, initializing tls...");
+                config.connect_async(&domain, stream).map_err(tls_err)
+            })
+            .and_then(|stream| {
+                match stream.get_ref().ssl().selected_alpn_protocol() {
+                    Some(b"h2") => Ok(stream),
+                    protocol => Err(io::Error::other(format!("server did not negotiate h2 (alpn: {:?})",
+                                                             protocol.map(String::from_utf8_lossy)))),
+                }
+            })
+            .and_then(move |stream| h2::client::handshake(stream).map_err(h2_err))
+    }
+}
+
+/// State shared by the streams of an h2 client run (all on the same thread).
+struct H2Run {
+    rtt: HistLog,
+    split: Option<Split>,
+    tag: &'static str,
+    code: u16,
+    padding: usize,
+    run_id: u32,
+    conn: u32,
+    seq: u64,
+    limits: Limits,
+    /// whether `rtt` has been reset at the end of the warmup
+    warm: bool,
+    responses: Responses,
+    start: Instant,
+    logger: Logger,
+}
+
+impl H2Run {
+    /// Encodes the body of the next request, returning it with its `sent` timestamp.
+    fn next_body(&mut self) -> (Bytes, u64) {
+        let body = wire::Body {
+            code: self.code,
+            sent: crate::nanos(Utc::now()),
+            seq: self.seq,
+            conn: wire::conn_id(self.run_id, self.conn),
+            padding: self.padding,
+        };
+        self.seq += 1;
+        let mut buf = Vec::with_capacity(body.encoded_len());
+        body.encode(&mut buf).unwrap();
+        (buf.into(), body.sent)
+    }
+
+    /// Like `Worker::recording`.
+    fn recording(&mut self, t: Instant) -> bool {
+        if self.limits.is_warmup(t) { return false }
+        if !self.warm {
+            self.warm = true;
+            self.rtt.reset();
+            if let Some(ref mut split) = self.split {
+                split.reset();
+            }
+        }
+        true
+    }
+
+    /// Records the round trip of a request taken from the run's limits at `taken`,
+    /// unless that was during the warmup.
+    fn record(&mut self, taken: Instant, write_start: Instant, sent: u64, resp: &http::Response<h2::RecvStream>) {
+        if !self.recording(taken) { return }
+        let rcvd = Instant::now();
+        let t4 = crate::nanos(Utc::now()) as i64;
+        self.responses.count(resp.status().as_u16());
+        if self.responses.total() % crate::HEARTBEAT_EVERY == 0 {
+            info!(self.logger, "sent {} requests in {:?}", self.responses.total().thousands_sep(), Instant::now() - self.start;
+                  "responses" => %self.responses);
+        }
+        if !resp.status().is_success() {
+            debug!(self.logger, "error response"; "status" => resp.status().as_u16());
+            return
+        }
+        self.rtt.record(histlog::nanos(rcvd - write_start)).ok();
+        self.rtt.check_send(rcvd);
+        let header = |name| {
+            resp.headers().get(name)
+                .and_then(|value: &http::header::HeaderValue| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        if let (Some(t2), Some(t3)) = (header(wire::RECV_HEADER), header(wire::SEND_HEADER)) {
+            let x = Exchange { t1: sent as i64, t2: t2 as i64, t3: t3 as i64, t4 };
+            let (rtt, tag) = (&self.rtt, self.tag);
+            let split = self.split.get_or_insert_with(|| Split::new(rtt, tag));
+            if split.record(&x, rcvd) {
+                info!(self.logger, "clock offset estimate"; "nanos" => split.offset.estimate());
+            }
+        }
+    }
+}
+
+/// Sends requests on one stream at a time of the connection behind `send`, until
+/// `stop` is set, the run's limits are reached or a request fails.
+fn h2_stream(
+    send: h2::client::SendRequest<Bytes>,
+    uri: http::Uri,
+    run: Rc<RefCell<H2Run>>,
+    stop: Arc<AtomicBool>,
+    throttle: Option<Duration>,
+) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn(send, move |send| {
+        // also decides the warmup, since `send.ready()` may wait for a stream
+        let taken = Instant::now();
+        if stop.load(Ordering::Relaxed) || !run.borrow().limits.take(taken) {
+            return Either::A(future::ok(Loop::Break(())))
+        }
+        let uri = uri.clone();
+        let failed = Rc::clone(&run);
+        let run = Rc::clone(&run);
+        let request = send.ready().and_then(move |mut send| {
+            let (body, sent) = run.borrow_mut().next_body();
+            let req = http::Request::post(uri)
+                .header(http::header::CONTENT_LENGTH, body.len())
+                .body(())
+                .unwrap();
+            let write_start = Instant::now();
+            let sent_request = send.send_request(req, false).and_then(|(resp, mut stream)| {
+                stream.send_data(body, true).map(|()| resp)
+            });
+            match sent_request {
+                Ok(resp) => Either::A(resp.map(move |resp| (send, resp, write_start, sent, run))),
+                Err(e) => Either::B(future::err(e)),
+            }
+        }).map_err(move |e| {
+            let mut run = failed.borrow_mut();
+            warn!(run.logger, "h2 request failed: {:?}", e);
+            if !run.limits.is_warmup(taken) {
+                run.responses.dropped += 1;
+                run.limits.give_back(taken);
+            }
+        }).and_then(move |(send, resp, write_start, sent, run)| {
+            run.borrow_mut().record(taken, write_start, sent, &resp);
+            let logger = run.borrow().logger.clone();
+            let mut body = resp.into_body();
+            future::poll_fn(move || {
+                while let Some(chunk) = try_ready!(body.poll()) {
+                    body.release_capacity().release_capacity(chunk.len())?;
+                }
+                Ok(Async::Ready(()))
+            }).map(move |()| send).map_err(move |e: h2::Error| {
+                warn!(logger, "h2 response body failed: {:?}", e);
+            })
+        }).and_then(move |send| {
+            match throttle {
+                Some(throttle) => Either::A(tokio::timer::Delay::new(Instant::now() + throttle)
+                    .map(move |()| send)
+                    .map_err(|_| ())),
+                None => Either::B(future::ok(send)),
+            }
+        }).map(Loop::Continue);
+        Either::B(request)
+    })
+}
+
+pub fn h2_client(
+    addr: http::Uri,
+    streams: usize,
+    tls: TlsOptions,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Outcome> {
+    let logger = logger.new(o!(
+        "thread" => "client[h2+tls[openssl]]",
+        "addr" => addr.to_string(),
+        "host" => addr.host().unwrap().to_string(),
+        "streams" => streams,
+    ));
+    if tls.resume {
+        warn!(logger, "--tls-resume is not supported by the h2 client, ignoring");
+    }
+    let client = H2Tls::new(addr, streams, tls)
+        .map_err(|e| {
+            error!(logger, "failed to build SslConnector: {:?}", e);
+        }).expect("SslConnector::builder(SslMethod::tls())");
+    h2_run(client, stop, settings, &logger)
+}
+
+/// Spawns a thread sending requests with `client` until `stop` is set, or the run's
+/// `settings.duration` or `settings.requests` is up, returning the responses
+/// received and the latency summary of the run (the h2 counterpart of `run`).
+pub fn h2_run(
+    client: H2Tls,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Outcome> {
+    let logger = logger.clone();
+    let code = 13;
+    let tag = client.tag;
+    let (rtt, mut pinned) = rtt_log(settings, "h2_client", tag, &logger);
+    let core = settings.cores.first().cloned();
+    if settings.rate.is_some() || settings.calibrate > 0 {
+        warn!(logger, "--rate and --calibrate are not supported by the h2 client, ignoring");
+    }
+    if settings.connections > 1 || settings.threads > 1 {
+        warn!(logger, "--connections and --threads are not supported by the h2 client \
+                       (see --h2-streams), ignoring");
+    }
+    if settings.pipeline_depth > 1 {
+        warn!(logger, "--pipeline-depth is not supported by the h2 client (see --h2-streams), ignoring");
+    }
+    if settings.connection_per_request {
+        warn!(logger, "--connection-per-request is not supported by the h2 client, ignoring");
+    }
+    let throttle = settings.throttle;
+    let padding = settings.padding;
+    let warmup = settings.warmup;
+    let limits = Limits::new(settings, Instant::now());
+    thread::spawn(move || {
+        pinned.extend(core.map(|core| affinity::pin("worker-0", core, &logger)));
+        save_meta(&rtt, &pinned, &logger);
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let uri: http::Uri = format!("https://{}/{}/", client.addr.authority_part().unwrap(), crate::REQUEST_PATH)
+            .parse()
+            .unwrap();
+        let run = Rc::new(RefCell::new(H2Run {
+            rtt,
+            split: None,
+            tag,
+            code,
+            padding,
+            run_id: wire::new_run_id(),
+            conn: 0,
+            seq: 0,
+            limits,
+            warm: warmup.is_none(),
+            responses: Responses::default(),
+            start: Instant::now(),
+            logger: logger.clone(),
+        }));
+        if let Some(warmup) = warmup {
+            info!(logger, "warming up"; "warmup" => format!("{:?}", warmup));
+        }
+
+        while !stop.load(Ordering::Relaxed) && !run.borrow().limits.exhausted(Instant::now()) {
+            let (send, connection) = match rt.block_on(client.connect(&logger)) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!(logger, "failed to connect: {:?}", e);
+                    warn!(logger, "sleeping 1s on connection error before retry");
+                    thread::sleep(Duration::from_secs(1));
+                    continue
+                }
+            };
+            run.borrow_mut().conn += 1;
+            let conn_logger = logger.clone();
+            rt.spawn(connection.map_err(move |e| {
+                warn!(conn_logger, "h2 connection error: {:?}", e);
+            }));
+            let streams = (0..client.streams).map(|_| {
+                h2_stream(send.clone(), uri.clone(), Rc::clone(&run), Arc::clone(&stop), throttle)
+            });
+            if rt.block_on(future::join_all(streams)).is_err() {
+                warn!(logger, "h2 stream failed, reconnecting");
+            }
+        }
+        let (responses, log) = {
+            let run = run.borrow();
+            info!(logger, "client finished"; "responses" => %run.responses, "seq" => run.seq, "connections" => run.conn, "run_id" => run.run_id);
+            (run.responses, run.rtt.path().to_path_buf())
+        };
+        // the streams are done with `run`: dropping its histograms flushes the log
+        drop(rt);
+        drop(run);
+        Outcome { responses, report: read_report(&log, &logger) }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn test_settings() -> Settings {
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        Settings { hist_dir: hist_dir.to_str().unwrap().to_string(), ..Default::default() }
+    }
+
+    /// Responds to `n` requests, then sets `stop` on receiving the next one (by which
+    /// time the client has read every response). Returns the number of connections
+    /// accepted.
+    fn serve(listener: TcpListener, n: usize, keep_alive: bool, stop: Arc<AtomicBool>) -> thread::JoinHandle<usize> {
+        let resp: &[u8] = if keep_alive {
+            b"HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n"
+        } else {
+            b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"
+        };
+        thread::spawn(move || {
+            let mut n_rcvd = 0;
+            let mut n_conns = 0;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                n_conns += 1;
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 512];
+                'conn: loop {
+                    let n_read = stream.read(&mut chunk).unwrap();
+                    if n_read == 0 { break 'conn }
+                    buf.extend_from_slice(&chunk[..n_read]);
+                    while let Some((body, len)) = request_body(&buf) {
+                        wire::Body::decode(&buf[body..len]).unwrap();
+                        buf.drain(..len);
+                        if n_rcvd == n {
+                            stop.store(true, Ordering::Relaxed);
+                            return n_conns
+                        }
+                        stream.write_all(resp).unwrap();
+                        n_rcvd += 1;
+                        if !keep_alive { break 'conn }
                     }
+                }
+            }
+            n_conns
+        })
+    }
 
-                    n_sent_stream += 1;
+    /// Responds to the requests of one connection with `resps` in turn, then sets
+    /// `stop` on receiving the next one.
+    fn serve_responses(listener: TcpListener, resps: Vec<&'static [u8]>, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut resps = resps.into_iter();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 512];
+            loop {
+                let n_read = stream.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n_read]);
+                while let Some((_, len)) = request_body(&buf) {
+                    buf.drain(..len);
+                    match resps.next() {
+                        Some(resp) => stream.write_all(resp).unwrap(),
+                        None => return stop.store(true, Ordering::Relaxed),
+                    }
+                }
+            }
+        })
+    }
 
-                    //stream.shutdown(Shutdown::Write).expect("shutdown write");
-                    trace!(logger, "awaiting resp");
-                    let mut bytes_rcvd = 0;
-                    'd: loop {
-                        match stream.read(&mut rcv[bytes_rcvd..]) {
-                            Ok(n) => {
-                                bytes_rcvd += n;
-                                trace!(logger, "{} bytes rcvd: {}", bytes_rcvd, str::from_utf8(&rcv[..bytes_rcvd]).unwrap());
-                            }
+    /// Responds to `n` requests over any number of concurrent keep-alive connections,
+    /// then sets `stop` on receiving the next one. Returns the number of connections
+    /// accepted and of distinct client runs seen.
+    fn serve_concurrent(listener: TcpListener, n: usize, stop: Arc<AtomicBool>) -> thread::JoinHandle<(usize, usize)> {
+        use std::collections::HashSet;
+        use std::sync::atomic::AtomicUsize;
 
-                            Err(e) => {
-                                #[cfg(feature = "trace")]
-                                {
-                                    if e.kind() != io::ErrorKind::WouldBlock {
-                                        trace!(logger, "stream.read err: {:?}", e);
-                                    }
-                                }
+        let n_rcvd = Arc::new(AtomicUsize::new(0));
+        let runs = Arc::new(Mutex::new(HashSet::new()));
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            let mut n_conns = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let mut stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(1));
+                        continue
+                    }
+                    Err(e) => panic!("{:?}", e),
+                };
+                stream.set_nonblocking(false).unwrap();
+                n_conns += 1;
+                let (n_rcvd, runs, stop) = (Arc::clone(&n_rcvd), Arc::clone(&runs), Arc::clone(&stop));
+                thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 512];
+                    loop {
+                        let n_read = match stream.read(&mut chunk) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n_read) => n_read,
+                        };
+                        buf.extend_from_slice(&chunk[..n_read]);
+                        while let Some((body, len)) = request_body(&buf) {
+                            let body = wire::Body::decode(&buf[body..len]).unwrap();
+                            runs.lock().unwrap().insert(wire::run_id(body.conn));
+                            buf.drain(..len);
+                            if n_rcvd.fetch_add(1, Ordering::Relaxed) >= n {
+                                stop.store(true, Ordering::Relaxed);
+                                return
                             }
+                            stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n").unwrap();
                         }
+                    }
+                });
+            }
+            let n_runs = runs.lock().unwrap().len();
+            (n_conns, n_runs)
+        })
+    }
+
+    /// Returns the start of the body and the end of the request, if complete.
+    fn request_body(buf: &[u8]) -> Option<(usize, usize)> {
+        let end = jetscii::ByteSubstring::new(b"\r\n\r\n").find(buf)? + 4;
+        let head = str::from_utf8(&buf[..end]).unwrap();
+        let content_length: usize = head.lines()
+            .find(|line| line.starts_with("Content-Length: "))
+            .map(|line| line["Content-Length: ".len()..].parse().unwrap())
+            .unwrap_or(0);
+        if buf.len() >= end + content_length { Some((end, end + content_length)) } else { None }
+    }
 
-                        if stop.load(Ordering::Relaxed) { break 'b }
+    fn logger() -> Logger {
+        Logger::root(slog::Discard, o!())
+    }
 
-                        //if &rcv[..bytes_rcvd] == HTTP_204 { break 'c }
-                        if let Some(i) = jetscii::ByteSubstring::new(b"\r\n\r\n").find(&rcv[..bytes_rcvd]) {
-                            trace!(logger, "rcvd resp:\n {}", unsafe { str::from_utf8_unchecked(&rcv[..i]) });
-                            n_sent += 1;
-                            break 'd
+    #[test]
+    fn run_reuses_keep_alive_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = serve(listener, 50, true, Arc::clone(&stop));
+        let n_sent = run(client, stop, &test_settings(), &logger()).join().unwrap().responses.ok;
+        assert_eq!(n_sent, 50);
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn run_reconnects_when_server_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = serve(listener, 10, false, Arc::clone(&stop));
+        let n_sent = run(client, stop, &test_settings(), &logger()).join().unwrap().responses.ok;
+        assert_eq!(n_sent, 10);
+        // one more for the request that stopped the server
+        assert_eq!(server.join().unwrap(), 11);
+    }
+
+    #[test]
+    fn run_opens_connection_per_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        assert!(client.request_head(false).ends_with("Connection: close\r\n"));
+        let stop = Arc::new(AtomicBool::new(false));
+        // the server would keep the connections alive
+        let server = serve(listener, 10, true, Arc::clone(&stop));
+        let settings = Settings { connection_per_request: true, ..test_settings() };
+        let n_sent = run(client, stop, &settings, &logger()).join().unwrap().responses.ok;
+        assert_eq!(n_sent, 10);
+        // one more for the request that stopped the server
+        assert_eq!(server.join().unwrap(), 11);
+    }
+
+    #[test]
+    fn run_counts_error_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut resps: Vec<&'static [u8]> = Vec::new();
+        for _ in 0..5 {
+            resps.push(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         3\r\nabc\r\n0\r\n\r\n");
+            resps.push(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 11\r\n\r\nunavailable");
+            resps.push(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+        }
+        let server = serve_responses(listener, resps, Arc::clone(&stop));
+        let responses = run(client, stop, &test_settings(), &logger()).join().unwrap().responses;
+        // the request left unanswered as the server stops may be dropped
+        assert!(responses.dropped <= 1, "{}", responses);
+        assert_eq!(Responses { dropped: 0, ..responses }, Responses { ok: 5, client_errors: 5, server_errors: 5, ..Default::default() });
+        assert_eq!(responses.errors(), 10);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn run_at_constant_rate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = serve(listener, 20, true, Arc::clone(&stop));
+        let settings = Settings { rate: NonZeroU64::new(1_000), ..test_settings() };
+        let start = Instant::now();
+        let n_sent = run(client, stop, &settings, &logger()).join().unwrap().responses.ok;
+        assert_eq!(n_sent, 20);
+        // 20th request is not scheduled until 19ms after the first
+        assert!(Instant::now() - start >= Duration::from_millis(19));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn run_spreads_connections_across_threads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = serve_concurrent(listener, 200, Arc::clone(&stop));
+        let settings = Settings { connections: 4, threads: 2, ..test_settings() };
+        let n_sent = run(client, stop, &settings, &logger()).join().unwrap().responses.ok;
+        // responses on the other connections may still be in flight when stop is set
+        assert!((197..=200).contains(&n_sent), "n_sent = {}", n_sent);
+        // each connection is a client run of its own
+        assert_eq!(server.join().unwrap(), (4, 4));
+    }
+
+    #[test]
+    fn run_pipelines_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        // responds only once it has 4 requests buffered, all at once, which would
+        // deadlock a client that waits on each response before sending the next
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 512];
+            let mut seqs = Vec::new();
+            while seqs.len() < 100 {
+                let mut pending = Vec::new();
+                let mut end = 0;
+                while pending.len() < 4 {
+                    match request_body(&buf[end..]) {
+                        Some((body, len)) => {
+                            pending.push(wire::Body::decode(&buf[end + body..end + len]).unwrap().seq);
+                            end += len;
+                        }
+                        None => {
+                            let n_read = stream.read(&mut chunk).unwrap();
+                            assert!(n_read > 0);
+                            buf.extend_from_slice(&chunk[..n_read]);
                         }
                     }
+                }
+                buf.drain(..end);
+                stream.write_all(&b"HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n".repeat(4)).unwrap();
+                seqs.extend(pending);
+            }
+            server_stop.store(true, Ordering::Relaxed);
+            seqs
+        });
+        let settings = Settings { pipeline_depth: 4, ..test_settings() };
+        let n_sent = run(client, stop, &settings, &logger()).join().unwrap().responses.ok;
+        // the last batch of responses may still be in flight when stop is set
+        assert!((96..=100).contains(&n_sent), "n_sent = {}", n_sent);
+        assert_eq!(server.join().unwrap(), (0..100).collect::<Vec<u64>>());
+    }
 
-                    if jetscii::ByteSubstring::new(b"keep-alive").find(&rcv[..bytes_rcvd]).is_none() { return () }
+    #[test]
+    fn run_stops_after_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        // never sets `stop`: the client stops on its own
+        serve(listener, 1000, true, Arc::clone(&stop));
+        let settings = Settings { requests: Some(25), pipeline_depth: 4, ..test_settings() };
+        let outcome = run(client, Arc::clone(&stop), &settings, &logger()).join().unwrap();
+        assert_eq!(outcome.responses, Responses { ok: 25, ..Default::default() });
+        assert!(!stop.load(Ordering::Relaxed));
+        // every round trip recorded makes it into the summary
+        let report = outcome.report.unwrap();
+        assert_eq!(report.series(), "raw_tcp_client");
+        let tags: Vec<(&str, u64)> = report.tags.iter().map(|summary| (summary.tag.as_str(), summary.count())).collect();
+        assert_eq!(tags, vec![("raw-tcp{pipeline=4}", 25)]);
+    }
 
-                    //thread::sleep(Duration::from_millis(1));
+    #[test]
+    fn run_resends_requests_dropped_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        // answers the first of 4 pipelined requests, then closes the connection on
+        // the others; answers every request of the connections after that
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let (mut buf, mut chunk, mut n_rcvd) = (Vec::new(), [0u8; 512], 0);
+                while i > 0 || n_rcvd < 4 {
+                    let n_read = match stream.read(&mut chunk) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n_read) => n_read,
+                    };
+                    buf.extend_from_slice(&chunk[..n_read]);
+                    while let Some((_, len)) = request_body(&buf) {
+                        buf.drain(..len);
+                        n_rcvd += 1;
+                        if i == 0 && n_rcvd < 4 { continue }
+                        stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n").unwrap();
+                    }
+                }
+            }
+        });
+        let settings = Settings { requests: Some(10), pipeline_depth: 4, ..test_settings() };
+        let responses = run(client, stop, &settings, &logger()).join().unwrap().responses;
+        // a fifth request may have been sent before the connection was closed
+        assert!((3..=4).contains(&responses.dropped), "{}", responses);
+        assert_eq!(responses.ok, 10, "{}", responses);
+    }
 
-                    //if n_sent_stream > 75 { return () }
+    #[test]
+    fn run_discards_warmup_and_stops_after_duration() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        serve(listener, 1000, true, Arc::clone(&stop));
+        let settings = Settings {
+            throttle: Some(Duration::from_millis(2)),
+            warmup: Some(Duration::from_millis(100)),
+            duration: Some(Duration::from_millis(100)),
+            ..test_settings()
+        };
+        let start = Instant::now();
+        let n_sent = run(client, stop, &settings, &logger()).join().unwrap().responses.ok;
+        assert!(Instant::now() - start >= Duration::from_millis(200));
+        // at most one request per throttle after the warmup
+        assert!((1..=51).contains(&n_sent), "n_sent = {}", n_sent);
+    }
 
-                    #[cfg(any(feature = "trace", feature = "debug"))]
-                    thread::sleep(Duration::from_secs(1));
+    /// Responds 204 to every request of one h2 connection over TLS, setting `stop`
+    /// after `n`. Returns the path of the server's certificate, named after `name`.
+    fn serve_h2(name: &str, n: usize, stop: Arc<AtomicBool>) -> (http::Uri, String, thread::JoinHandle<usize>) {
+        use openssl::ssl::{AlpnError, SslAcceptor, SslFiletype};
+        use tokio_openssl::SslAcceptorExt;
+
+        let dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        let (cert, cert_path, key_path) = crate::testing::write_self_signed(&dir, name);
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key_file(&key_path, SslFiletype::PEM).unwrap();
+        acceptor.set_alpn_select_callback(|_, protos| {
+            openssl::ssl::select_next_proto(b"\x02h2", protos).ok_or(AlpnError::NOACK)
+        });
+        let acceptor = acceptor.build();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("https://localhost:{}", listener.local_addr().unwrap().port()).parse().unwrap();
+        let server = thread::spawn(move || {
+            let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+            let listener = tokio::net::TcpListener::from_std(listener, &Default::default()).unwrap();
+            let (stream, _) = rt.block_on(listener.incoming().into_future()).ok().unwrap();
+            let stream = rt.block_on(acceptor.accept_async(stream.unwrap())).unwrap();
+            let connection = rt.block_on(h2::server::handshake(stream)).unwrap();
+            let n_rcvd = Rc::new(RefCell::new(0));
+            let counter = Rc::clone(&n_rcvd);
+            rt.block_on(connection.for_each(move |(req, mut respond)| {
+                assert_eq!(req.uri().path(), "/rust-http-benchmarks/");
+                respond.send_response(http::Response::builder().status(204).body(()).unwrap(), true).unwrap();
+                *counter.borrow_mut() += 1;
+                if *counter.borrow() == n { stop.store(true, Ordering::Relaxed) }
+                Ok(())
+            })).unwrap();
+            let n_rcvd = *n_rcvd.borrow();
+            n_rcvd
+        });
+        (uri, cert_path, server)
+    }
+
+    #[test]
+    fn h2_client_sends_on_concurrent_streams() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (uri, cert, server) = serve_h2("h2-streams", 100, Arc::clone(&stop));
+        let client = H2Tls::new(uri, 4, TlsOptions { ca_file: Some(cert), ..Default::default() }).unwrap();
+        let n_sent = h2_run(client, stop, &test_settings(), &logger()).join().unwrap().responses.ok;
+        // requests already in flight on the other streams when stop is set complete
+        assert!((100..104).contains(&n_sent), "n_sent = {}", n_sent);
+        assert_eq!(server.join().unwrap(), n_sent);
+    }
+
+    #[test]
+    fn h2_client_stops_after_requests() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (uri, _, server) = serve_h2("h2-requests", 1000, Arc::clone(&stop));
+        let client = H2Tls::new(uri, 4, TlsOptions { insecure: true, ..Default::default() }).unwrap();
+        let settings = Settings { requests: Some(50), ..test_settings() };
+        let n_sent = h2_run(client, Arc::clone(&stop), &settings, &logger()).join().unwrap().responses.ok;
+        assert_eq!(n_sent, 50);
+        assert!(!stop.load(Ordering::Relaxed));
+        // the connection is closed when the client finishes
+        assert_eq!(server.join().unwrap(), 50);
+    }
+
+    #[test]
+    fn tls_options_are_encoded_in_tag() {
+        assert_eq!(TlsOptions::default().tag_suffix(), None);
+        let tls = TlsOptions {
+            ciphers: Some("AES128-SHA".to_string()),
+            max_version: Some(SslVersion::TLS1_2),
+            resume: true,
+            servername: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(tls.tag_suffix().unwrap(), "{ciphers=AES128-SHA;max=1.2;resume}");
+        let client = OpensslTcp::new("https://localhost:443".parse().unwrap(), tls).unwrap();
+        assert_eq!(client.tag(), "raw-tcp+tls[openssl]{ciphers=AES128-SHA;max=1.2;resume}");
+        // the interval log drops tags with commas or spaces
+        let tls = TlsOptions { ciphers: Some("AES128-SHA,AES256-SHA".to_string()), ..Default::default() };
+        let client = OpensslTcp::new("https://localhost:443".parse().unwrap(), tls).unwrap();
+        assert_eq!(client.tag(), "raw-tcp+tls[openssl]{ciphers=AES128-SHA_AES256-SHA}");
+    }
+
+    #[test]
+    fn resumes_tls_session() {
+        use crate::servers;
+
+        let dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        let (_, cert, key) = crate::testing::write_self_signed(&dir, "resume");
+        let settings = servers::Settings {
+            hist_dir: dir.to_str().unwrap().to_string(),
+            interval: Duration::from_secs(30),
+            echo_timestamps: false,
+            tls: Some(servers::Tls { cert: cert.clone(), key }),
+            core: None,
+            hist_core: None,
+        };
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let _server = servers::std_server(addr, Default::default(), &settings, &logger());
+        let tls = TlsOptions { resume: true, ca_file: Some(cert), servername: Some("localhost".to_string()), ..Default::default() };
+        let client = OpensslTcp::new(format!("https://{}", addr).parse().unwrap(), tls).unwrap();
+        let connect = || {
+            for _ in 0..100 {
+                if let Ok(stream) = client.connect(&logger()) {
+                    return client.handshake(stream, &logger()).unwrap()
                 }
-            }).ok();
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("server not listening")
+        };
+
+        let mut stream = connect();
+        assert!(!stream.ssl().session_reused());
+        // exchange a request, so a TLS 1.3 session ticket sent after the handshake is read
+        let body = format!("v1 12 {} 0 0", crate::nanos(Utc::now()));
+        let req = format!("{}Content-Length: {}\r\n\r\n{}", client.request_head(true), body.len(), body);
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut buf = [0u8; 512];
+        let mut n = 0;
+        while client.parse_response(&buf[..n], false).unwrap().is_none() {
+            match stream.read(&mut buf[n..]) {
+                Ok(n_read) => n += n_read,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{:?}", e),
+            }
         }
-        n_sent
-    })
-}
+        assert!(buf.starts_with(b"HTTP/1.1 204"));
+        assert!(connect().ssl().session_reused());
+    }
 
+    #[test]
+    fn calibrates_against_server_clock() {
+        // server clock one second ahead of the client's
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RawTcp::new(listener.local_addr().unwrap()).unwrap();
+        thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 512];
+            loop {
+                let n_read = stream.read(&mut chunk).unwrap();
+                if n_read == 0 { return }
+                buf.extend_from_slice(&chunk[..n_read]);
+                while let Some((_, len)) = request_body(&buf) {
+                    assert!(buf.starts_with(format!("GET {} ", wire::CALIBRATION_PATH).as_bytes()));
+                    buf.drain(..len);
+                    let time = crate::nanos(Utc::now()) + 1_000_000_000;
+                    let resp = format!("HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\
+                                        {}: {}\r\n{}: {}\r\n\r\n",
+                                       wire::RECV_HEADER, time, wire::SEND_HEADER, time);
+                    stream.write_all(resp.as_bytes()).unwrap();
+                }
+            }
+        });
+        let calibration = calibrate(&client, 100, &AtomicBool::new(false), &logger()).unwrap();
+        assert_eq!(calibration.probes, 100);
+        assert!((calibration.offset - 1_000_000_000).abs() < 10_000_000, "{:?}", calibration);
+    }
 
+    #[test]
+    fn schedule_is_fixed_timeline() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(NonZeroU64::new(100).unwrap(), start, 1, 0);
+        let first = schedule.next();
+        let second = schedule.next();
+        let third = schedule.next();
+        assert_eq!(first, start);
+        assert_eq!(second - first, Duration::from_millis(10));
+        assert_eq!(third - first, Duration::from_millis(20));
+
+        // connection 1 of 4 takes every fourth slot, starting with the second
+        let mut schedule = Schedule::new(NonZeroU64::new(100).unwrap(), start, 4, 1);
+        assert_eq!(schedule.next() - start, Duration::from_millis(10));
+        assert_eq!(schedule.next() - start, Duration::from_millis(50));
+
+        // above 1e9 per second, the slots are still spread out
+        let mut schedule = Schedule::new(NonZeroU64::new(3_000_000_000).unwrap(), start, 1, 0);
+        let slots: Vec<Duration> = (0..7).map(|_| schedule.next() - start).collect();
+        assert_eq!(slots[3], Duration::from_nanos(1));
+        assert_eq!(slots[6], Duration::from_nanos(2));
+    }
+}
@@ -0,0 +1,200 @@
+//! Estimating the offset between the client's and the server's clocks from the
+//! timestamps of a request/response exchange.
+//!
+//! For a request sent by the client at `t1`, received by the server at `t2`, answered
+//! at `t3` and received back by the client at `t4` (all in nanoseconds since the
+//! unix epoch, by each side's clock), the NTP-style estimates are:
+//!
+//! ```text
+//! offset = ((t2 - t1) + (t3 - t4)) / 2    (server clock minus client clock)
+//! delay  = (t4 - t1) - (t3 - t2)          (round trip, minus time spent in the server)
+//! ```
+//!
+//! The offset estimate assumes symmetric network paths and is only as good as the
+//! delay is low, so estimates favor the samples with the lowest delay.
+
+use serde::{Serialize, Serializer};
+
+/// Timestamps of one exchange, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exchange {
+    pub t1: i64,
+    pub t2: i64,
+    pub t3: i64,
+    pub t4: i64,
+}
+
+impl Exchange {
+    pub fn offset(&self) -> i64 {
+        ((self.t2 - self.t1) + (self.t3 - self.t4)) / 2
+    }
+
+    pub fn delay(&self) -> i64 {
+        (self.t4 - self.t1) - (self.t3 - self.t2)
+    }
+}
+
+/// Running estimate of the clock offset: the offset of the exchange with the lowest
+/// delay in the last complete window of exchanges (or the current window, until one
+/// is complete). Windowing lets the estimate follow drift over a long run.
+#[derive(Debug, Clone)]
+pub struct OffsetEstimator {
+    window: u32,
+    n: u32,
+    /// (delay, offset) of the best exchange in the current window
+    best: Option<(i64, i64)>,
+    estimate: Option<i64>,
+}
+
+impl OffsetEstimator {
+    pub fn new(window: u32) -> Self {
+        assert!(window > 0);
+        Self { window, n: 0, best: None, estimate: None }
+    }
+
+    /// Adds an exchange, returning the updated estimate.
+    pub fn update(&mut self, x: &Exchange) -> i64 {
+        let delay = x.delay();
+        match self.best {
+            Some((best, _)) if best <= delay => {}
+            _ => self.best = Some((delay, x.offset())),
+        }
+        let (_, offset) = self.best.unwrap();
+        self.n += 1;
+        if self.n >= self.window {
+            self.estimate = Some(offset);
+            self.best = None;
+            self.n = 0;
+        }
+        self.estimate.unwrap_or(offset)
+    }
+
+    pub fn estimate(&self) -> Option<i64> {
+        self.estimate.or_else(|| self.best.map(|(_, offset)| offset))
+    }
+}
+
+/// Estimate of the clock offset and drift from a calibration run of many exchanges.
+///
+/// The exchanges are split into `buckets` consecutive groups and the lowest-delay
+/// exchange of each group is kept; a least squares line through those offsets, over
+/// time, gives the offset at the start of the run and its drift.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// client time of the first exchange, nanoseconds since the unix epoch
+    pub t0: i64,
+    /// offset at `t0` (server clock minus client clock), in nanoseconds
+    pub offset: i64,
+    /// change of the offset per unit of time (nanoseconds per nanosecond)
+    pub drift: f64,
+    /// lowest delay of all exchanges
+    pub min_delay: i64,
+    pub probes: usize,
+}
+
+impl Calibration {
+    pub fn fit(exchanges: &[Exchange], buckets: usize) -> Option<Self> {
+        let first = exchanges.first()?;
+        let t0 = first.t1;
+        let chunk = (exchanges.len() / buckets.max(1)).max(1);
+        let points: Vec<(f64, f64)> = exchanges.chunks(chunk)
+            .filter_map(|xs| xs.iter().min_by_key(|x| x.delay()))
+            .map(|x| (((x.t1 + x.t4) / 2 - t0) as f64, x.offset() as f64))
+            .collect();
+        let n = points.len() as f64;
+        let mean_t = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_offset = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let var_t: f64 = points.iter().map(|p| (p.0 - mean_t).powi(2)).sum();
+        let cov: f64 = points.iter().map(|p| (p.0 - mean_t) * (p.1 - mean_offset)).sum();
+        let drift = if var_t > 0.0 { cov / var_t } else { 0.0 };
+        Some(Calibration {
+            t0,
+            offset: (mean_offset - drift * mean_t).round() as i64,
+            drift,
+            min_delay: exchanges.iter().map(|x| x.delay()).min()?,
+            probes: exchanges.len(),
+        })
+    }
+
+    /// Estimated offset at client time `t` (nanoseconds since the unix epoch).
+    pub fn offset_at(&self, t: i64) -> i64 {
+        self.offset + (self.drift * (t - self.t0) as f64).round() as i64
+    }
+
+    /// Drift in parts per billion (nanoseconds per second).
+    pub fn drift_ppb(&self) -> f64 {
+        self.drift * 1e9
+    }
+
+}
+
+/// `{"t0":..,"offset_nanos":..,"drift_ppb":..,"min_delay_nanos":..,"probes":..}`
+impl Serialize for Calibration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Json {
+            t0: i64,
+            offset_nanos: i64,
+            drift_ppb: f64,
+            min_delay_nanos: i64,
+            probes: usize,
+        }
+        Json {
+            t0: self.t0,
+            offset_nanos: self.offset,
+            drift_ppb: (self.drift_ppb() * 1e3).round() / 1e3,
+            min_delay_nanos: self.min_delay,
+            probes: self.probes,
+        }.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_offset_from_lowest_delay() {
+        // server clock 1_000 ahead, 100 each way, 50 in the server
+        let fast = Exchange { t1: 0, t2: 1_100, t3: 1_150, t4: 250 };
+        assert_eq!(fast.offset(), 1_000);
+        assert_eq!(fast.delay(), 200);
+        // response delayed by 400 in a queue somewhere
+        let slow = Exchange { t1: 0, t2: 1_100, t3: 1_150, t4: 650 };
+        assert_eq!(slow.delay(), 600);
+
+        let mut estimator = OffsetEstimator::new(2);
+        assert_eq!(estimator.update(&slow), slow.offset());
+        assert_eq!(estimator.update(&fast), 1_000);
+        // new window: keeps the last window's estimate until it completes
+        assert_eq!(estimator.update(&slow), 1_000);
+        assert_eq!(estimator.update(&slow), slow.offset());
+    }
+
+    #[test]
+    fn fits_offset_and_drift() {
+        // server clock starts 5_000 ahead and gains 1_000 ppb; every third exchange
+        // is delayed by queueing on the way back
+        let xs: Vec<Exchange> = (0..3_000i64).map(|i| {
+            let t1 = 1_000_000_000 + i * 1_000_000;
+            let offset = 5_000 + i;
+            let queued = if i % 3 == 0 { 0 } else { 40_000 };
+            Exchange { t1, t2: t1 + 10_000 + offset, t3: t1 + 12_000 + offset, t4: t1 + 22_000 + queued }
+        }).collect();
+        let calibration = Calibration::fit(&xs, 30).unwrap();
+        assert_eq!(calibration.min_delay, 20_000);
+        assert_eq!(calibration.probes, 3_000);
+        assert!((calibration.drift_ppb() - 1_000.0).abs() < 1.0, "{:?}", calibration);
+        assert!((calibration.offset - 5_000).abs() <= 1, "{:?}", calibration);
+        assert!((calibration.offset_at(xs[2_999].t1) - 7_999).abs() <= 1);
+    }
+
+    #[test]
+    fn single_exchange_has_no_drift() {
+        let x = Exchange { t1: 0, t2: 1_100, t3: 1_150, t4: 250 };
+        let calibration = Calibration::fit(&[x], 10).unwrap();
+        assert_eq!(calibration.offset, 1_000);
+        assert_eq!(calibration.drift, 0.0);
+        assert!(Calibration::fit(&[], 10).is_none());
+    }
+}
@@ -0,0 +1,403 @@
+//! Compares two benchmark runs by series and tag: the change of each percentile
+//! from the baseline to the candidate, with a bootstrap confidence interval
+//! telling real differences apart from noise.
+//!
+//! Runs read from interval logs keep their histograms, which are resampled (a
+//! Poisson bootstrap of the count of each recorded value) to find the spread of
+//! each percentile's relative change. Runs read from json summaries only have the
+//! percentiles, so their changes can't be tested.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use hdrhistogram::Histogram;
+use pretty_toa::ThousandsSep;
+use rand::SeedableRng;
+use rand::distributions::{Distribution, Poisson};
+use rand::rngs::SmallRng;
+use serde::Deserialize;
+use crate::report::{self, Interval, SeriesJson, SummaryJson, PERCENTILES};
+
+/// Latency of a tag in one of the runs compared.
+pub enum Latency {
+    /// merged histogram, in nanoseconds
+    Hist(Histogram<u64>),
+    /// `PERCENTILES`, then the max, in nanoseconds
+    Percentiles(Vec<u64>),
+}
+
+impl Latency {
+    /// `PERCENTILES`, then the max.
+    fn values(&self) -> Vec<u64> {
+        match self {
+            Latency::Hist(hist) => values(hist),
+            Latency::Percentiles(values) => values.clone(),
+        }
+    }
+}
+
+fn values(hist: &Histogram<u64>) -> Vec<u64> {
+    PERCENTILES.iter()
+        .map(|p| hist.value_at_quantile(p / 100.0))
+        .chain(Some(hist.max()))
+        .collect()
+}
+
+/// Series (e.g. "raw_tcp_client") and tag a latency was recorded under. The same
+/// tag may be recorded by a client (round trips) and a server (one-way latency).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Key {
+    pub series: String,
+    pub tag: String,
+}
+
+impl Key {
+    pub fn new(series: &str, tag: &str) -> Self {
+        Self { series: series.to_string(), tag: tag.to_string() }
+    }
+}
+
+/// `<series>/<tag>`
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.series, self.tag)
+    }
+}
+
+/// One side of a comparison: the latency of each series and tag.
+#[derive(Default)]
+pub struct Run {
+    pub tags: Vec<(Key, Latency)>,
+}
+
+impl Run {
+    /// Reads the interval logs and json summaries (`.json`, as saved by
+    /// `--summary-json` or next to a server's log) at `paths`, which may be
+    /// directories of logs. The intervals of a tag are merged across the logs of
+    /// the same series.
+    pub fn read<P: AsRef<Path>>(paths: &[P]) -> io::Result<Self> {
+        let mut by_series: BTreeMap<String, Vec<Interval>> = BTreeMap::new();
+        let mut summaries = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                summaries.extend(read_summary(path)?);
+                continue
+            }
+            for log in report::find_logs(path)? {
+                by_series.entry(report::series(&log).to_string()).or_default().extend(report::read_log(&log)?);
+            }
+        }
+        let mut run = Run::default();
+        let tags = by_series.iter()
+            .flat_map(|(series, intervals)| {
+                report::summarize(intervals).into_iter()
+                    .map(move |summary| (Key::new(series, &summary.tag), Latency::Hist(summary.hist)))
+            })
+            .chain(summaries);
+        for (key, latency) in tags {
+            if run.get(&key).is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} read more than once", key)))
+            }
+            run.tags.push((key, latency));
+        }
+        Ok(run)
+    }
+
+    fn get(&self, key: &Key) -> Option<&Latency> {
+        self.tags.iter().find(|(k, _)| k == key).map(|(_, latency)| latency)
+    }
+}
+
+/// Either json summary: saved by `--summary-json` (see `report::write_json`), or
+/// next to a server's log (see `ByClient::finish`).
+#[derive(Deserialize)]
+struct SummaryFile {
+    series: Option<BTreeMap<String, SeriesJson>>,
+    latency: Option<BTreeMap<String, SummaryJson>>,
+}
+
+/// Reads the latencies of the json summary at `path`. Those saved next to a
+/// server's log are named after its series, like the log.
+fn read_summary(path: &Path) -> io::Result<Vec<(Key, Latency)>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg));
+    let file: SummaryFile = serde_json::from_slice(&fs::read(path)?).map_err(|e| invalid(e.to_string()))?;
+    let latency = |series: &str, tags: BTreeMap<String, SummaryJson>| -> Vec<(Key, Latency)> {
+        tags.into_iter().map(|(tag, json)| (Key::new(series, &tag), Latency::Percentiles(json.values()))).collect()
+    };
+    match file {
+        SummaryFile { series: Some(series), .. } => {
+            Ok(series.into_iter().flat_map(|(name, series)| latency(&name, series.tags)).collect())
+        }
+        SummaryFile { latency: Some(tags), .. } => Ok(latency(report::series(path), tags)),
+        _ => Err(invalid("not a latency summary".to_string())),
+    }
+}
+
+/// Settings of the bootstrap.
+#[derive(Clone, Debug)]
+pub struct Bootstrap {
+    /// number of times each histogram is resampled
+    pub resamples: usize,
+    /// of the confidence intervals, in percent (e.g. 95.0)
+    pub confidence: f64,
+    /// of the random number generator, so that results are reproducible
+    pub seed: u64,
+}
+
+impl Default for Bootstrap {
+    fn default() -> Self {
+        Self { resamples: 1000, confidence: 95.0, seed: 0 }
+    }
+}
+
+/// A histogram drawn from `hist`: each value recorded a Poisson distributed number
+/// of times, with the count of `hist` as the mean.
+fn resample(hist: &Histogram<u64>, rng: &mut SmallRng) -> Histogram<u64> {
+    let mut resampled = Histogram::new_from(hist);
+    for value in hist.iter_recorded() {
+        let count = Poisson::new(value.count_at_value() as f64).sample(rng);
+        resampled.record_n(value.value_iterated_to(), count).ok();
+    }
+    resampled
+}
+
+/// Change of one percentile of a tag (or of its max).
+pub struct Delta {
+    pub key: Key,
+    /// "p50", .. "max"
+    pub stat: String,
+    /// nanoseconds
+    pub baseline: u64,
+    /// nanoseconds
+    pub candidate: u64,
+    /// confidence interval of the relative change, in percent, if both runs were
+    /// read from interval logs (never for the max)
+    pub ci: Option<(f64, f64)>,
+}
+
+impl Delta {
+    /// Relative change, in percent.
+    pub fn relative(&self) -> f64 {
+        relative(self.baseline, self.candidate)
+    }
+
+    /// Whether the confidence interval leaves out 0.
+    pub fn significant(&self) -> bool {
+        self.ci.is_some_and(|(lo, hi)| lo > 0.0 || hi < 0.0)
+    }
+
+    /// Whether the percentile got slower by more than `threshold` percent: by its
+    /// relative change, which must also be significant if it could be tested. The
+    /// max is left out, being a single sample.
+    pub fn regression(&self, threshold: f64) -> bool {
+        self.stat != "max" && self.relative() > threshold && (self.ci.is_none() || self.significant())
+    }
+}
+
+fn relative(baseline: u64, candidate: u64) -> f64 {
+    match baseline {
+        0 if candidate == 0 => 0.0,
+        0 => f64::INFINITY,
+        _ => (candidate as f64 - baseline as f64) / baseline as f64 * 100.0,
+    }
+}
+
+/// Value at `quantile` of `sorted`.
+fn quantile(sorted: &[f64], quantile: f64) -> f64 {
+    let i = ((sorted.len() - 1) as f64 * quantile).round() as usize;
+    sorted[i]
+}
+
+/// Outcome of `compare`.
+pub struct Comparison {
+    /// by series and tag, then percentile
+    pub deltas: Vec<Delta>,
+    pub only_baseline: Vec<Key>,
+    pub only_candidate: Vec<Key>,
+    pub confidence: f64,
+}
+
+impl Comparison {
+    /// The changes that are `Delta::regression`s.
+    pub fn regressions(&self, threshold: f64) -> Vec<&Delta> {
+        self.deltas.iter().filter(|delta| delta.regression(threshold)).collect()
+    }
+}
+
+/// Aligns the series and tags of `baseline` and `candidate`, and finds the change
+/// of each percentile of those in both.
+pub fn compare(baseline: &Run, candidate: &Run, bootstrap: &Bootstrap) -> Comparison {
+    let mut rng = SmallRng::seed_from_u64(bootstrap.seed);
+    let stats: Vec<String> = PERCENTILES.iter().map(|p| format!("p{}", p)).chain(Some("max".to_string())).collect();
+    let mut deltas = Vec::new();
+    let mut only_baseline = Vec::new();
+    for (key, base) in &baseline.tags {
+        let cand = match candidate.get(key) {
+            Some(cand) => cand,
+            None => {
+                only_baseline.push(key.clone());
+                continue
+            }
+        };
+        // relative change of each percentile, by resample
+        let mut resampled: Vec<Vec<f64>> = vec![Vec::new(); PERCENTILES.len()];
+        if let (Latency::Hist(base), Latency::Hist(cand)) = (base, cand) {
+            for _ in 0..bootstrap.resamples {
+                let (base, cand) = (values(&resample(base, &mut rng)), values(&resample(cand, &mut rng)));
+                for (i, changes) in resampled.iter_mut().enumerate() {
+                    changes.push(relative(base[i], cand[i]));
+                }
+            }
+        }
+        let tail = (1.0 - bootstrap.confidence / 100.0) / 2.0;
+        let (base, cand) = (base.values(), cand.values());
+        for (i, stat) in stats.iter().enumerate() {
+            let ci = resampled.get_mut(i).filter(|changes| !changes.is_empty()).map(|changes| {
+                changes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                (quantile(changes, tail), quantile(changes, 1.0 - tail))
+            });
+            deltas.push(Delta { key: key.clone(), stat: stat.clone(), baseline: base[i], candidate: cand[i], ci });
+        }
+    }
+    let only_candidate = candidate.tags.iter()
+        .filter(|(key, _)| baseline.get(key).is_none())
+        .map(|(key, _)| key.clone())
+        .collect();
+    Comparison { deltas, only_baseline, only_candidate, confidence: bootstrap.confidence }
+}
+
+/// Table of every change, latencies in microseconds. Significant changes are
+/// marked `*`.
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys: Vec<String> = self.deltas.iter().map(|delta| delta.key.to_string()).collect();
+        let width = keys.iter().map(String::len).chain(Some(10)).max().unwrap();
+        writeln!(f, "{:<width$} {:>7} {:>12} {:>12} {:>9} {:>21}", "series/tag", "", "baseline", "candidate", "change",
+                 format!("{}% ci", self.confidence), width = width)?;
+        for (delta, key) in self.deltas.iter().zip(&keys) {
+            let ci = match delta.ci {
+                Some((lo, hi)) => format!("[{:+.1}%, {:+.1}%]", lo, hi),
+                None => "-".to_string(),
+            };
+            writeln!(f, "{:<width$} {:>7} {:>12} {:>12} {:>+8.1}% {:>21}{}", key, delta.stat,
+                     format!("{:.1}", delta.baseline as f64 / 1e3), format!("{:.1}", delta.candidate as f64 / 1e3),
+                     delta.relative(), ci, if delta.significant() { " *" } else { "" }, width = width)?;
+        }
+        for key in &self.only_baseline {
+            writeln!(f, "{}: only in the baseline", key)?;
+        }
+        for key in &self.only_candidate {
+            writeln!(f, "{}: only in the candidate", key)?;
+        }
+        let count = self.deltas.iter().filter(|delta| delta.significant()).count();
+        write!(f, "({} significant change(s) of {}; latencies in microseconds)", count.thousands_sep(),
+               self.deltas.len().thousands_sep())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use histlog::HistLog;
+
+    fn hist(values: impl Iterator<Item = u64>) -> Latency {
+        let mut hist = Histogram::new(3).unwrap();
+        for value in values {
+            hist.record(value).unwrap();
+        }
+        Latency::Hist(hist)
+    }
+
+    fn run(tags: Vec<(&str, Latency)>) -> Run {
+        Run { tags: tags.into_iter().map(|(tag, latency)| (Key::new("client", tag), latency)).collect() }
+    }
+
+    #[test]
+    fn flags_significant_regressions() {
+        // the same distribution, and one 20% slower
+        let baseline = run(vec![
+            ("same", hist((0..10_000).map(|i| 1_000_000 + i * 100))),
+            ("slower", hist((0..10_000).map(|i| 1_000_000 + i * 100))),
+            ("gone", hist(0..10)),
+        ]);
+        let candidate = run(vec![
+            ("same", hist((0..10_000).map(|i| 1_000_050 + i * 100))),
+            ("slower", hist((0..10_000).map(|i| 1_200_000 + i * 120))),
+            ("new", hist(0..10)),
+        ]);
+        let comparison = compare(&baseline, &candidate, &Bootstrap { resamples: 200, ..Default::default() });
+        assert_eq!(comparison.only_baseline, vec![Key::new("client", "gone")]);
+        assert_eq!(comparison.only_candidate, vec![Key::new("client", "new")]);
+        assert_eq!(comparison.deltas.len(), 2 * (PERCENTILES.len() + 1));
+
+        let p50 = |tag| comparison.deltas.iter().find(|delta| delta.key.tag == tag && delta.stat == "p50").unwrap();
+        assert!(!p50("same").significant(), "{:?}", p50("same").ci);
+        let slower = p50("slower");
+        assert!(slower.significant());
+        let (lo, hi) = slower.ci.unwrap();
+        assert!(lo < 20.0 && 20.0 < hi && hi - lo < 5.0, "{:?}", slower.ci);
+
+        let regressions: Vec<(&str, &str)> = comparison.regressions(10.0).iter()
+            .map(|delta| (delta.key.tag.as_str(), delta.stat.as_str()))
+            .collect();
+        assert_eq!(regressions, vec![("slower", "p50"), ("slower", "p90"), ("slower", "p99"), ("slower", "p99.9"), ("slower", "p99.99")]);
+        assert!(comparison.regressions(25.0).is_empty());
+        assert!(comparison.to_string().lines().nth(1).unwrap().starts_with("client/same       p50"));
+    }
+
+    #[test]
+    fn reads_json_summaries() {
+        let dir = std::env::temp_dir().join("rust-http-benchmarks-test").join("compare");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let (base, cand) = (dir.join("base.json"), dir.join("std_server.2026-10-18-10:54:07Z.summary.json"));
+        fs::write(&base, r#"{"version":"0.2.3","series":{"std_server":{"log":"x","tags":{"raw-tcp":
+            {"count":10,"throughput":1.0,"p50":1000,"p90":2000,"p99":3000,"p99.9":4000,"p99.99":5000,"max":6000}}}}}"#).unwrap();
+        fs::write(&cand, r#"{"version":"0.2.3","tags":{"raw-tcp":{"received":10}},"latency":{"raw-tcp":
+            {"count":10,"throughput":1.0,"p50":1000,"p90":2000,"p99":3300,"p99.9":4000,"p99.99":5000,"max":9000}}}"#).unwrap();
+        let comparison = compare(&Run::read(&[&base]).unwrap(), &Run::read(&[&cand]).unwrap(), &Default::default());
+        assert!(comparison.deltas.iter().all(|delta| delta.key == Key::new("std_server", "raw-tcp")));
+        let changes: Vec<(&str, f64, bool)> = comparison.deltas.iter()
+            .map(|delta| (delta.stat.as_str(), delta.relative().round(), delta.ci.is_some()))
+            .collect();
+        assert_eq!(changes, vec![("p50", 0.0, false), ("p90", 0.0, false), ("p99", 10.0, false),
+                                 ("p99.9", 0.0, false), ("p99.99", 0.0, false), ("max", 50.0, false)]);
+        // untested changes count as they are, except the max
+        let regressions: Vec<&str> = comparison.regressions(5.0).iter().map(|delta| delta.stat.as_str()).collect();
+        assert_eq!(regressions, vec!["p99"]);
+        assert!(Run::read(&[dir.join("missing.json")]).is_err());
+        fs::write(&base, r#"{"version":"0.2.3"}"#).unwrap();
+        assert!(Run::read(&[&base]).is_err());
+    }
+
+    #[test]
+    fn keys_logs_and_summaries_alike() {
+        let dir = std::env::temp_dir().join("rust-http-benchmarks-test").join("compare-keys");
+        fs::remove_dir_all(&dir).ok();
+        let mut rtt = HistLog::new(&dir, "compare_client", "raw-tcp", Duration::from_secs(30)).unwrap();
+        let mut server = HistLog::new(&dir, "compare_server", "raw-tcp", Duration::from_secs(30)).unwrap();
+        for nanos in 1..=100 {
+            rtt.record(nanos * 2000).unwrap();
+            server.record(nanos * 1000).unwrap();
+        }
+        let log = rtt.path().to_path_buf();
+        drop((rtt, server));
+        let json = dir.join("summary.json");
+        report::write_json(&json, &[report::Report::read(&log).unwrap()]).unwrap();
+
+        // the client's and server's "raw-tcp" are kept apart
+        let logs = Run::read(&[&dir]).unwrap();
+        let keys: Vec<&Key> = logs.tags.iter().map(|(key, _)| key).collect();
+        assert!(keys.contains(&&Key::new("compare_client", "raw-tcp")));
+        assert!(keys.contains(&&Key::new("compare_server", "raw-tcp")));
+        let comparison = compare(&logs, &Run::read(&[&json]).unwrap(), &Default::default());
+        assert!(comparison.only_candidate.is_empty());
+        let p50 = comparison.deltas.iter().find(|delta| delta.stat == "p50").unwrap();
+        assert_eq!(p50.key, Key::new("compare_client", "raw-tcp"));
+        assert_eq!(p50.relative(), 0.0);
+    }
+}
@@ -2,15 +2,29 @@
 extern crate clap;
 #[macro_use]
 extern crate slog;
-#[macro_use]
 extern crate jetscii;
 
+use std::fs;
+use std::io;
 use std::net::ToSocketAddrs;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::affinity::Pinned;
 
 pub mod servers;
 pub mod clients;
+pub mod wire;
+pub mod response;
+pub mod sequence;
+pub mod report;
+pub mod clock;
+pub mod affinity;
+pub mod compare;
+#[doc(hidden)]
+pub mod testing;
 
 pub(crate) const HEARTBEAT_EVERY: usize = 1_000;
 pub(crate) const REQUEST_PATH: &str = "rust-http-benchmarks";
@@ -30,11 +44,65 @@ pub fn client_tag(n: u16) -> Option<&'static str> {
         9 => Some("chttp-wolfssl-ECDHE-RSA-AES128-SHA"),
         10 => Some("hyper-http-via-stunnel"),
         11 => Some("raw-tcp"),
-        12 => Some("raw tcp+tls[openssl]"),
+        12 => Some("raw-tcp+tls[openssl]"),
+        13 => Some("h2+tls[openssl]"),
         _ => None
     }
 }
 
+/// `HistLog` tags must be `&'static str`; this leaks `tag` to get one. Intended
+/// for tags built once at startup, not per request.
+///
+/// The interval log silently drops tags containing whitespace or commas (e.g. from
+/// a `--tls-ciphers` list), so those are replaced with `_`.
+pub(crate) fn leak_tag(tag: String) -> &'static str {
+    let tag = tag.replace(|c: char| c == ',' || c.is_whitespace(), "_");
+    Box::leak(tag.into_boxed_str())
+}
+
+/// Path of a file saved alongside the interval log at `log`, named after it with
+/// `suffix` in place of its extension: `<series>.<time>.<suffix>`.
+pub(crate) fn sidecar_path(log: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let name = log.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.replace("hdrhistogram-interval-log.v2.gz", suffix))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad interval log path"))?;
+    Ok(log.with_file_name(name))
+}
+
+/// Saves the metadata of a run next to its interval log at `log`, as
+/// `<series>.<time>.meta.json`: the version, and the cores threads were pinned to.
+pub(crate) fn write_meta(log: &Path, affinity: &[Pinned]) -> io::Result<PathBuf> {
+    #[derive(Serialize)]
+    struct Meta<'a> {
+        affinity: &'a [Pinned],
+    }
+    write_json(log, "meta.json", &Meta { affinity })
+}
+
+/// The version, then the fields of `T`.
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    version: &'static str,
+    #[serde(flatten)]
+    fields: &'a T,
+}
+
+/// Saves a json object of the version and the fields of `fields` next to the
+/// interval log at `log`, as `<series>.<time>.<suffix>`.
+pub(crate) fn write_json<T: Serialize>(log: &Path, suffix: &str, fields: &T) -> io::Result<PathBuf> {
+    let path = sidecar_path(log, suffix)?;
+    write_versioned(&path, fields)?;
+    Ok(path)
+}
+
+/// Saves a json object of the version and the fields of `fields` at `path`.
+pub(crate) fn write_versioned<T: Serialize>(path: &Path, fields: &T) -> io::Result<()> {
+    let mut json = serde_json::to_vec(&Versioned { version: crate_version!(), fields })?;
+    json.push(b'\n');
+    fs::write(path, json)
+}
+
 #[inline]
 pub fn nanos(t: DateTime<Utc>) -> u64 {
     (t.timestamp() as u64) * 1_000_000_000_u64 + (t.timestamp_subsec_nanos() as u64)
@@ -57,6 +125,13 @@ pub fn validate_uint(s: String) -> Result<(), String> {
      }).map(|_| ())
 }
 
+#[doc(hide)]
+pub fn validate_positive_uint(s: String) -> Result<(), String> {
+     NonZeroU64::from_str(&s).map_err(|e| {
+         format!("{} (expected positive integer)", e)
+     }).map(|_| ())
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,422 @@
+//! Latency summaries read back from the interval logs: the histograms of each tag
+//! merged across intervals, their percentiles over the whole run or over time, and
+//! their percentile distributions. Used by both binaries on exit, and by `analyze`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use hdrhistogram::Histogram;
+use hdrhistogram::serialization::Deserializer;
+use hdrhistogram::serialization::interval_log::{IntervalLogIterator, LogEntry};
+use pretty_toa::ThousandsSep;
+use serde::{Deserialize, Serialize};
+
+/// Percentiles reported, along with the max.
+pub const PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 99.99];
+
+/// A histogram of an interval log.
+pub struct Interval {
+    /// empty if the interval is untagged
+    pub tag: String,
+    /// since the unix epoch
+    pub start: Duration,
+    pub duration: Duration,
+    /// nanoseconds
+    pub hist: Histogram<u64>,
+}
+
+impl Interval {
+    pub fn end(&self) -> Duration {
+        self.start + self.duration
+    }
+}
+
+fn invalid<E: fmt::Debug>(path: &Path, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", path.display(), e))
+}
+
+/// Interval logs at `path`: the file itself, or the files under the directory
+/// whose names contain "interval-log" (e.g. `<series>.<time>.hdrhistogram-interval-log.v2.gz`),
+/// sorted by path.
+pub fn find_logs(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()])
+    }
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            logs.extend(find_logs(&path)?);
+        } else if path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.contains("interval-log")) {
+            logs.push(path);
+        }
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+/// Series name of the interval log at `log`, from its file name
+/// (`<series>.<time>.hdrhistogram-interval-log.v2.gz`), e.g. "raw_tcp_client".
+pub fn series(log: &Path) -> &str {
+    log.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .unwrap_or("")
+}
+
+/// Reads every interval of the log at `path`, in the order written.
+pub fn read_log(path: &Path) -> io::Result<Vec<Interval>> {
+    let buf = fs::read(path)?;
+    let mut deserializer = Deserializer::new();
+    let mut base = Duration::default();
+    let mut intervals = Vec::new();
+    for entry in IntervalLogIterator::new(&buf) {
+        match entry.map_err(|e| invalid(path, e))? {
+            LogEntry::BaseTime(time) => base = time,
+            LogEntry::StartTime(_) => {}
+            LogEntry::Interval(interval) => {
+                let bytes = base64::decode(interval.encoded_histogram()).map_err(|e| invalid(path, e))?;
+                let hist = deserializer.deserialize(&mut &bytes[..]).map_err(|e| invalid(path, e))?;
+                intervals.push(Interval {
+                    tag: interval.tag().map(|tag| tag.as_str().to_string()).unwrap_or_default(),
+                    start: base + interval.start_timestamp(),
+                    duration: interval.duration(),
+                    hist,
+                });
+            }
+        }
+    }
+    Ok(intervals)
+}
+
+/// The intervals of one tag, merged.
+pub struct Summary {
+    pub tag: String,
+    /// nanoseconds
+    pub hist: Histogram<u64>,
+    /// start of the first interval, since the unix epoch
+    pub start: Duration,
+    /// end of the last interval, since the unix epoch
+    pub end: Duration,
+}
+
+impl Summary {
+    pub fn count(&self) -> u64 {
+        self.hist.len()
+    }
+
+    /// Values recorded per second, over the span of the intervals.
+    pub fn throughput(&self) -> f64 {
+        let secs = (self.end - self.start).as_secs_f64();
+        if secs > 0.0 { self.count() as f64 / secs } else { 0.0 }
+    }
+
+    /// Value at `percentile` (0 to 100), in nanoseconds.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        self.hist.value_at_quantile(percentile / 100.0)
+    }
+
+    pub fn json(&self) -> SummaryJson {
+        let p = |i: usize| self.percentile(PERCENTILES[i]);
+        SummaryJson {
+            count: self.count(),
+            throughput: (self.throughput() * 10.0).round() / 10.0,
+            p50: p(0),
+            p90: p(1),
+            p99: p(2),
+            p99_9: p(3),
+            p99_99: p(4),
+            max: self.hist.max(),
+        }
+    }
+}
+
+/// A `Summary` as saved in json, with latencies in nanoseconds: a field for each
+/// of `PERCENTILES`, then the max.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SummaryJson {
+    pub count: u64,
+    /// values per second
+    pub throughput: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    #[serde(rename = "p99.9")]
+    pub p99_9: u64,
+    #[serde(rename = "p99.99")]
+    pub p99_99: u64,
+    pub max: u64,
+}
+
+impl SummaryJson {
+    /// `PERCENTILES`, then the max.
+    pub fn values(&self) -> Vec<u64> {
+        vec![self.p50, self.p90, self.p99, self.p99_9, self.p99_99, self.max]
+    }
+}
+
+/// Merges `intervals` by tag, sorted by tag, leaving out tags with no values
+/// recorded.
+pub fn summarize<'a, I: IntoIterator<Item = &'a Interval>>(intervals: I) -> Vec<Summary> {
+    let mut summaries: Vec<Summary> = Vec::new();
+    for interval in intervals {
+        if interval.hist.is_empty() { continue }
+        match summaries.iter_mut().find(|summary| summary.tag == interval.tag) {
+            Some(summary) => {
+                summary.hist.add(&interval.hist).ok();
+                summary.start = summary.start.min(interval.start);
+                summary.end = summary.end.max(interval.end());
+            }
+            None => {
+                // deserialized histograms only cover the values they hold
+                let mut hist = Histogram::new(interval.hist.sigfig()).unwrap();
+                hist.add(&interval.hist).ok();
+                summaries.push(Summary { tag: interval.tag.clone(), hist, start: interval.start, end: interval.end() })
+            }
+        }
+    }
+    summaries.sort_by(|a, b| a.tag.cmp(&b.tag));
+    summaries
+}
+
+/// Merges `intervals` by tag into windows `width` long, starting at `origin`
+/// (intervals are placed by their start; those starting earlier are left out).
+/// Returns the start of each window, relative to `origin`, with its summaries.
+pub fn over_time<'a, I>(intervals: I, origin: Duration, width: Duration) -> Vec<(Duration, Vec<Summary>)>
+    where I: IntoIterator<Item = &'a Interval>
+{
+    let width = width.as_nanos().max(1);
+    let mut windows: Vec<(u128, Vec<&Interval>)> = Vec::new();
+    for interval in intervals.into_iter().filter(|interval| interval.start >= origin) {
+        let n = (interval.start - origin).as_nanos() / width;
+        match windows.iter_mut().find(|(m, _)| *m == n) {
+            Some((_, window)) => window.push(interval),
+            None => windows.push((n, vec![interval])),
+        }
+    }
+    windows.sort_by_key(|&(n, _)| n);
+    windows.into_iter()
+        .map(|(n, window)| (Duration::from_nanos((n * width) as u64), summarize(window)))
+        .filter(|(_, summaries)| !summaries.is_empty())
+        .collect()
+}
+
+fn write_header(f: &mut dyn fmt::Write, width: usize) -> fmt::Result {
+    write!(f, "{:<width$} {:>12} {:>12}", "tag", "count", "per sec", width = width)?;
+    for &p in PERCENTILES.iter() {
+        write!(f, " {:>10}", format!("p{}", p))?;
+    }
+    writeln!(f, " {:>10}", "max")
+}
+
+fn write_row(f: &mut dyn fmt::Write, summary: &Summary, width: usize) -> fmt::Result {
+    write!(f, "{:<width$} {:>12} {:>12}", summary.tag, summary.count().thousands_sep(),
+           (summary.throughput().round() as u64).thousands_sep(), width = width)?;
+    for &p in PERCENTILES.iter() {
+        write!(f, " {:>10.1}", summary.percentile(p) as f64 / 1e3)?;
+    }
+    writeln!(f, " {:>10.1}", summary.hist.max() as f64 / 1e3)
+}
+
+fn tag_width<'a, I: IntoIterator<Item = &'a Summary>>(summaries: I) -> usize {
+    summaries.into_iter().map(|summary| summary.tag.len()).chain(Some(3)).max().unwrap()
+}
+
+/// Percentile table of `summaries`, a row per tag, latencies in microseconds.
+pub fn table(summaries: &[Summary]) -> String {
+    let width = tag_width(summaries);
+    let mut table = String::new();
+    write_header(&mut table, width).unwrap();
+    for summary in summaries {
+        write_row(&mut table, summary, width).unwrap();
+    }
+    table
+}
+
+/// Percentile table of each window of `over_time`, a row per window and tag,
+/// latencies in microseconds.
+pub fn over_time_table(windows: &[(Duration, Vec<Summary>)]) -> String {
+    let width = tag_width(windows.iter().flat_map(|(_, summaries)| summaries));
+    let mut table = format!("{:>10} ", "time (s)");
+    write_header(&mut table, width).unwrap();
+    for (start, summaries) in windows {
+        for summary in summaries {
+            table.push_str(&format!("{:>10.1} ", start.as_secs_f64()));
+            write_row(&mut table, summary, width).unwrap();
+        }
+    }
+    table
+}
+
+/// Percentile distribution of `hist`, in the format of HdrHistogram's
+/// `outputPercentileDistribution` (`.hgrm`, as read by its plotter), with
+/// `ticks_per_half_distance` percentile steps between each halving of the distance
+/// to 100%. Values are nanoseconds divided by `unit` (e.g. 1000.0 for microseconds).
+pub fn distribution(hist: &Histogram<u64>, ticks_per_half_distance: u32, unit: f64) -> String {
+    let mut out = format!("{:>12} {:>14} {:>10} {:>14}\n\n", "Value", "Percentile", "TotalCount", "1/(1-Percentile)");
+    let mut total = 0;
+    for value in hist.iter_quantiles(ticks_per_half_distance) {
+        total += value.count_since_last_iteration();
+        let quantile = value.quantile_iterated_to();
+        let scaled = value.value_iterated_to() as f64 / unit;
+        if quantile < 1.0 {
+            out.push_str(&format!("{:>12.3} {:>2.12} {:>10} {:>14.2}\n", scaled, quantile, total, 1.0 / (1.0 - quantile)));
+        } else {
+            out.push_str(&format!("{:>12.3} {:>2.12} {:>10}\n", scaled, quantile, total));
+        }
+    }
+    let sub_buckets = (2 * 10u64.pow(u32::from(hist.sigfig()))).next_power_of_two();
+    out.push_str(&format!("#[Mean    = {:>12.3}, StdDeviation   = {:>12.3}]\n", hist.mean() / unit, hist.stdev() / unit));
+    out.push_str(&format!("#[Max     = {:>12.3}, Total count    = {:>12}]\n", hist.max() as f64 / unit, hist.len()));
+    out.push_str(&format!("#[Buckets = {:>12}, SubBuckets     = {:>12}]\n", hist.buckets(), sub_buckets));
+    out
+}
+
+/// Latency summary of the interval log of a run, by tag.
+pub struct Report {
+    pub log: PathBuf,
+    pub tags: Vec<Summary>,
+}
+
+impl Report {
+    /// Reads the interval log at `log`, which must be complete (its `HistLog`s
+    /// dropped).
+    pub fn read(log: &Path) -> io::Result<Self> {
+        Ok(Self { log: log.to_path_buf(), tags: summarize(&read_log(log)?) })
+    }
+
+    /// Series name of the log, e.g. "raw_tcp_client".
+    pub fn series(&self) -> &str {
+        series(&self.log)
+    }
+
+    /// `Summary::json` by tag.
+    pub fn tags_json(&self) -> BTreeMap<String, SummaryJson> {
+        self.tags.iter().map(|summary| (summary.tag.clone(), summary.json())).collect()
+    }
+}
+
+/// Percentile table of every tag (see `table`).
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(latencies in microseconds, from {})", table(&self.tags), self.log.display())
+    }
+}
+
+/// Json of the reports of a run, by series, as saved by `write_json`:
+/// `{"version":..,"series":{"<series>":{"log":..,"tags":{"<tag>":<SummaryJson>,..}}}}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportsJson {
+    pub series: BTreeMap<String, SeriesJson>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeriesJson {
+    /// the interval log read
+    pub log: PathBuf,
+    pub tags: BTreeMap<String, SummaryJson>,
+}
+
+/// Saves `reports` as json at `path` (see `ReportsJson`).
+pub fn write_json(path: &Path, reports: &[Report]) -> io::Result<()> {
+    let series = reports.iter()
+        .map(|report| (report.series().to_string(), SeriesJson { log: report.log.clone(), tags: report.tags_json() }))
+        .collect();
+    crate::write_versioned(path, &ReportsJson { series })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use histlog::HistLog;
+
+    #[test]
+    fn reads_back_what_histlog_wrote() {
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test").join("report");
+        let mut rtt = HistLog::new(&hist_dir, "report_test", "rtt", Duration::from_millis(1)).unwrap();
+        let mut other = rtt.clone_with_tag("other");
+        let _empty = rtt.clone_with_tag("empty");
+        for nanos in 1..=1000u64 {
+            rtt.record(nanos * 1000).unwrap();
+            if nanos % 100 == 0 {
+                std::thread::sleep(Duration::from_millis(2));
+                rtt.check_send(std::time::Instant::now());
+            }
+        }
+        other.record(5).unwrap();
+        let log = rtt.path().to_path_buf();
+        drop((rtt, other, _empty));
+
+        assert!(find_logs(&hist_dir).unwrap().contains(&log));
+
+        assert!(read_log(&log).unwrap().iter().filter(|interval| interval.tag == "rtt").count() >= 10);
+        let report = Report::read(&log).unwrap();
+        assert_eq!(report.series(), "report_test");
+        let tags: Vec<&str> = report.tags.iter().map(|summary| summary.tag.as_str()).collect();
+        assert_eq!(tags, vec!["other", "rtt"]);
+        let rtt = &report.tags[1];
+        assert_eq!(rtt.count(), 1000);
+        assert!(rtt.throughput() > 0.0);
+        assert!((499_000..=501_000).contains(&rtt.percentile(50.0)), "p50 = {}", rtt.percentile(50.0));
+        assert!((999_000..=1_001_000).contains(&rtt.hist.max()));
+        let json = serde_json::to_string(&report.tags_json()).unwrap();
+        assert!(json.starts_with("{\"other\":{\"count\":1,\"throughput\":"), "{}", json);
+        assert!(json.contains(",\"p99.9\":"), "{}", json);
+
+        let table = report.to_string();
+        assert!(table.lines().next().unwrap().contains("p99.99"));
+        assert!(table.lines().nth(2).unwrap().starts_with("rtt "));
+    }
+
+    fn interval(tag: &str, start_millis: u64, values: &[u64]) -> Interval {
+        let mut hist = Histogram::new(3).unwrap();
+        for &value in values {
+            hist.record(value).unwrap();
+        }
+        Interval { tag: tag.to_string(), start: Duration::from_millis(start_millis), duration: Duration::from_millis(500), hist }
+    }
+
+    #[test]
+    fn merges_intervals_into_windows() {
+        let intervals = vec![
+            interval("a", 1000, &[1]),
+            interval("a", 1500, &[2, 3]),
+            interval("b", 1600, &[4]),
+            interval("a", 2200, &[5]),
+            interval("a", 4000, &[]),
+            interval("a", 4100, &[6]),
+            interval("a", 900, &[7]),
+        ];
+        let windows = over_time(&intervals, Duration::from_secs(1), Duration::from_secs(1));
+        let counts: Vec<(u64, Vec<(&str, u64)>)> = windows.iter()
+            .map(|(start, summaries)| {
+                (start.as_secs(), summaries.iter().map(|summary| (summary.tag.as_str(), summary.count())).collect())
+            }).collect();
+        assert_eq!(counts, vec![
+            (0, vec![("a", 3), ("b", 1)]),
+            (1, vec![("a", 1)]),
+            (3, vec![("a", 1)]),
+        ]);
+        // two half-second intervals merged, spanning a second
+        assert_eq!(windows[0].1[0].throughput(), 3.0);
+        assert_eq!(over_time_table(&windows).lines().count(), 5);
+    }
+
+    #[test]
+    fn prints_percentile_distribution() {
+        let values: Vec<u64> = (1..=1000).map(|micros| micros * 1000).collect();
+        let hist = interval("a", 0, &values).hist;
+        let out = distribution(&hist, 1, 1e3);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "       Value     Percentile TotalCount 1/(1-Percentile)");
+        assert_eq!(lines[2], "       1.000 0.000000000000          1           1.00");
+        assert!(lines[3].starts_with("     500.") && lines[3].ends_with(" 0.500000000000        500           2.00"), "{}", lines[3]);
+        let last = lines.iter().rev().find(|line| !line.starts_with('#')).unwrap();
+        assert!(last.ends_with(" 1.000000000000       1000"), "{}", last);
+        assert!(out.contains("#[Max     =     1000.447, Total count    =         1000]"), "{}", out);
+        assert!(out.ends_with("SubBuckets     =         2048]\n"));
+    }
+}
@@ -0,0 +1,258 @@
+//! Framing of the http/1.1 responses read by the raw tcp and openssl clients, on
+//! `httparse`.
+//!
+//! A response is complete once its head and body are in the receive buffer. The
+//! body is delimited as laid out in RFC 7230 section 3.3.3: none for 1xx, 204 and
+//! 304 responses, otherwise by chunked transfer coding, `Content-Length`, or the
+//! server closing the connection.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str;
+
+use crate::wire;
+
+/// Headers parsed per response; more than this is an error.
+const MAX_HEADERS: usize = 32;
+
+/// A complete response at the start of a receive buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    /// length of the status line and headers
+    pub head_len: usize,
+    /// length of the whole response: head, body and any chunked trailers
+    pub len: usize,
+    /// whether the connection can be reused for another request
+    pub keep_alive: bool,
+    /// value of `wire::RECV_HEADER`, if the server echoes timestamps
+    pub recv_nanos: Option<u64>,
+    /// value of `wire::SEND_HEADER`, if the server echoes timestamps
+    pub send_nanos: Option<u64>,
+}
+
+impl Response {
+    /// 1xx response sent ahead of the final one (e.g. `100 Continue`), which does
+    /// not answer the request. `101 Switching Protocols` is final.
+    pub fn is_interim(&self) -> bool {
+        self.status / 100 == 1 && self.status != 101
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status / 100 == 2
+    }
+}
+
+/// How the body of a response is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Empty,
+    Length(usize),
+    Chunked,
+    /// until the server closes the connection
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// malformed status line or headers
+    Head(httparse::Error),
+    /// `Content-Length` that is not a number (or too large), or conflicting values of it
+    ContentLength,
+    /// malformed chunk size line, or chunk not followed by CRLF
+    Chunk,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Head(ref e) => write!(f, "invalid response head: {}", e),
+            Error::ContentLength => write!(f, "invalid Content-Length"),
+            Error::Chunk => write!(f, "invalid chunked body"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parses the response at the start of `buf`, returning `None` until it is complete.
+///
+/// `eof` is whether the server has closed the connection, which completes a
+/// response whose body is delimited by the close.
+pub fn parse(buf: &[u8], eof: bool) -> Result<Option<Response>, Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut resp = httparse::Response::new(&mut headers);
+    let head_len = match resp.parse(buf).map_err(Error::Head)? {
+        httparse::Status::Complete(n) => n,
+        httparse::Status::Partial => return Ok(None),
+    };
+    let status = resp.code.unwrap();
+
+    // HTTP/1.1 connections persist unless either side says otherwise; HTTP/1.0
+    // ones only if the server says so
+    let mut keep_alive = resp.version == Some(1);
+    let mut chunked = false;
+    let mut transfer_encoding = false;
+    let mut content_length = None;
+    let (mut recv_nanos, mut send_nanos) = (None, None);
+    for header in resp.headers.iter() {
+        let value = str::from_utf8(header.value).unwrap_or("").trim();
+        if header.name.eq_ignore_ascii_case("Connection") {
+            for token in value.split(',').map(str::trim) {
+                if token.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if token.eq_ignore_ascii_case("keep-alive") && resp.version == Some(0) {
+                    keep_alive = true;
+                }
+            }
+        } else if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+            // chunked must be the final coding if present at all
+            transfer_encoding = true;
+            chunked = value.rsplit(',').next().map(str::trim)
+                .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+        } else if header.name.eq_ignore_ascii_case("Content-Length") {
+            let n = value.parse::<usize>().map_err(|_| Error::ContentLength)?;
+            if content_length.is_some_and(|prev| prev != n) {
+                return Err(Error::ContentLength)
+            }
+            content_length = Some(n);
+        } else if header.name.eq_ignore_ascii_case(wire::RECV_HEADER) {
+            recv_nanos = value.parse().ok();
+        } else if header.name.eq_ignore_ascii_case(wire::SEND_HEADER) {
+            send_nanos = value.parse().ok();
+        }
+    }
+
+    let framing = match status {
+        100..=199 | 204 | 304 => Framing::Empty,
+        _ if chunked => Framing::Chunked,
+        _ if transfer_encoding => Framing::Close,
+        _ => content_length.map_or(Framing::Close, Framing::Length),
+    };
+    let len = match framing {
+        Framing::Empty => Some(head_len),
+        Framing::Length(n) => Some(head_len.checked_add(n).ok_or(Error::ContentLength)?).filter(|&len| buf.len() >= len),
+        Framing::Chunked => chunked_len(&buf[head_len..])?.map(|n| head_len + n),
+        Framing::Close => {
+            keep_alive = false;
+            Some(buf.len()).filter(|_| eof)
+        }
+    };
+    Ok(len.map(|len| Response { status, head_len, len, keep_alive, recv_nanos, send_nanos }))
+}
+
+/// Length of the chunked body (including trailers) at the start of `buf`, if complete.
+fn chunked_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    let mut pos = 0;
+    loop {
+        let (n, size) = match httparse::parse_chunk_size(&buf[pos..]).map_err(|_| Error::Chunk)? {
+            httparse::Status::Complete(x) => x,
+            httparse::Status::Partial => return Ok(None),
+        };
+        pos += n;
+        if size == 0 { break }
+        // the chunk and its CRLF; a size this far past the buffer is no chunk
+        let end = usize::try_from(size).ok()
+            .and_then(|size| pos.checked_add(size)?.checked_add(2))
+            .ok_or(Error::Chunk)?;
+        if buf.len() < end { return Ok(None) }
+        if &buf[end - 2..end] != b"\r\n" { return Err(Error::Chunk) }
+        pos = end;
+    }
+    // trailer fields, up to an empty line
+    loop {
+        match buf[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(0) => return Ok(Some(pos + 2)),
+            Some(i) => pos += i + 2,
+            None => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(buf: &[u8]) -> Response {
+        parse(buf, false).unwrap().unwrap()
+    }
+
+    #[test]
+    fn frames_body_by_content_length() {
+        let buf = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1";
+        let resp = complete(buf);
+        assert_eq!((resp.status, resp.head_len, resp.len), (200, 38, 43));
+        assert!(resp.keep_alive && resp.is_success());
+        // every prefix short of the body is incomplete
+        for end in 0..43 {
+            assert_eq!(parse(&buf[..end], false), Ok(None), "end = {}", end);
+        }
+    }
+
+    #[test]
+    fn frames_chunked_body() {
+        let buf = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\nnext";
+        let resp = complete(buf);
+        assert_eq!(resp.len, buf.len() - 4);
+        for end in 0..resp.len {
+            assert_eq!(parse(&buf[..end], false), Ok(None), "end = {}", end);
+        }
+        assert_eq!(parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello!!", false),
+                   Err(Error::Chunk));
+        assert_eq!(parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n", false),
+                   Err(Error::Chunk));
+        assert_eq!(parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\nhello", false),
+                   Err(Error::Chunk));
+    }
+
+    #[test]
+    fn frames_body_by_close() {
+        let buf = b"HTTP/1.1 200 OK\r\n\r\nuntil the end";
+        assert_eq!(parse(buf, false), Ok(None));
+        let resp = parse(buf, true).unwrap().unwrap();
+        assert_eq!(resp.len, buf.len());
+        assert!(!resp.keep_alive);
+    }
+
+    #[test]
+    fn status_decides_empty_body() {
+        for &status in &["204 No Content", "304 Not Modified", "100 Continue"] {
+            let buf = format!("HTTP/1.1 {}\r\nContent-Length: 10\r\n\r\n", status);
+            assert_eq!(complete(buf.as_bytes()).len, buf.len(), "{}", status);
+        }
+        assert!(complete(b"HTTP/1.1 100 Continue\r\n\r\n").is_interim());
+        assert!(!complete(b"HTTP/1.1 101 Switching Protocols\r\n\r\n").is_interim());
+        let resp = complete(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        assert!(!resp.is_success() && !resp.is_interim());
+    }
+
+    #[test]
+    fn connection_semantics() {
+        let keep_alive = |head: &str| complete(format!("{}Content-Length: 0\r\n\r\n", head).as_bytes()).keep_alive;
+        assert!(keep_alive("HTTP/1.1 200 OK\r\n"));
+        assert!(!keep_alive("HTTP/1.1 200 OK\r\nConnection: close\r\n"));
+        assert!(!keep_alive("HTTP/1.1 200 OK\r\nconnection: Upgrade, Close\r\n"));
+        assert!(!keep_alive("HTTP/1.0 200 OK\r\n"));
+        assert!(keep_alive("HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn parses_echoed_timestamps() {
+        let resp = complete(b"HTTP/1.1 204 No Content\r\nx-recv-nanos: 1571234567891234567\r\n\
+                              X-Send-Nanos: 1571234567891299999\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(resp.recv_nanos, Some(1_571_234_567_891_234_567));
+        assert_eq!(resp.send_nanos, Some(1_571_234_567_891_299_999));
+        assert_eq!(complete(b"HTTP/1.1 204 No Content\r\n\r\n").recv_nanos, None);
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        assert!(matches!(parse(b"HTTP/1.1 abc\r\n\r\n", false), Err(Error::Head(_))));
+        assert_eq!(parse(b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n", false), Err(Error::ContentLength));
+        assert_eq!(parse(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n", false),
+                   Err(Error::ContentLength));
+        assert_eq!(parse(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", usize::MAX).as_bytes(), false),
+                   Err(Error::ContentLength));
+    }
+}
@@ -0,0 +1,146 @@
+//! Detects lost, duplicated and reordered requests from their sequence numbers.
+
+use std::fmt;
+use std::ops::AddAssign;
+use serde::Serialize;
+
+/// Number of sequence numbers below the highest one seen that are remembered, to
+/// tell late arrivals apart from duplicates.
+const WINDOW: u64 = 128;
+
+/// Counts of a `SeqTracker` (or several of them, added together).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SeqStats {
+    pub received: u64,
+    /// sequence numbers skipped over and not (yet) received
+    pub missing: u64,
+    pub duplicates: u64,
+    /// requests that arrived after one with a higher sequence number
+    pub out_of_order: u64,
+}
+
+impl AddAssign for SeqStats {
+    fn add_assign(&mut self, other: Self) {
+        self.received += other.received;
+        self.missing += other.missing;
+        self.duplicates += other.duplicates;
+        self.out_of_order += other.out_of_order;
+    }
+}
+
+impl fmt::Display for SeqStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "received={} missing={} duplicates={} out_of_order={}",
+               self.received, self.missing, self.duplicates, self.out_of_order)
+    }
+}
+
+/// What `SeqTracker::track` concluded about a sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    InOrder,
+    /// arrived after skipping `n` sequence numbers
+    Gap(u64),
+    Duplicate,
+    /// a sequence number previously counted as missing
+    Late,
+    /// `u64::MAX`, which no client sends (there'd be no next one); not counted
+    Invalid,
+}
+
+/// Tracks the sequence numbers of one stream of requests (one client run).
+///
+/// Sequence numbers below the first one seen are not counted as missing, since
+/// tracking may begin partway through a client's run.
+#[derive(Debug, Clone, Default)]
+pub struct SeqTracker {
+    /// one past the highest sequence number seen, or `None` before the first
+    next: Option<u64>,
+    /// bit `i` set if `next - 1 - i` was received
+    seen: u128,
+    stats: SeqStats,
+}
+
+impl SeqTracker {
+    pub fn new() -> Self { Default::default() }
+
+    pub fn stats(&self) -> SeqStats { self.stats }
+
+    pub fn track(&mut self, seq: u64) -> Arrival {
+        let after = match seq.checked_add(1) {
+            Some(after) => after,
+            None => return Arrival::Invalid,
+        };
+        self.stats.received += 1;
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                self.next = Some(after);
+                self.seen = 1;
+                return Arrival::InOrder
+            }
+        };
+
+        if seq >= next {
+            let skipped = seq - next;
+            let shift = skipped + 1;
+            self.seen = if shift >= WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = Some(after);
+            self.stats.missing += skipped;
+            return if skipped == 0 { Arrival::InOrder } else { Arrival::Gap(skipped) }
+        }
+
+        let age = next - 1 - seq;
+        if age < WINDOW && self.seen & (1 << age) != 0 {
+            self.stats.received -= 1;
+            self.stats.duplicates += 1;
+            return Arrival::Duplicate
+        }
+        if age < WINDOW {
+            self.seen |= 1 << age;
+        }
+        // beyond the window there's no telling a duplicate from a late arrival;
+        // assume the latter
+        self.stats.missing = self.stats.missing.saturating_sub(1);
+        self.stats.out_of_order += 1;
+        Arrival::Late
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_gaps_duplicates_and_reordering() {
+        let mut tracker = SeqTracker::new();
+        assert_eq!(tracker.track(10), Arrival::InOrder);
+        assert_eq!(tracker.track(11), Arrival::InOrder);
+        assert_eq!(tracker.track(14), Arrival::Gap(2));
+        assert_eq!(tracker.track(12), Arrival::Late);
+        assert_eq!(tracker.track(12), Arrival::Duplicate);
+        assert_eq!(tracker.track(14), Arrival::Duplicate);
+        assert_eq!(tracker.track(15), Arrival::InOrder);
+        assert_eq!(tracker.stats(), SeqStats { received: 5, missing: 1, duplicates: 2, out_of_order: 1 });
+    }
+
+    #[test]
+    fn large_gap_clears_window() {
+        let mut tracker = SeqTracker::new();
+        tracker.track(0);
+        assert_eq!(tracker.track(1000), Arrival::Gap(999));
+        assert_eq!(tracker.track(999), Arrival::Late);
+        assert_eq!(tracker.track(5), Arrival::Late);
+        assert_eq!(tracker.stats().missing, 997);
+    }
+
+    #[test]
+    fn ignores_max_sequence_number() {
+        let mut tracker = SeqTracker::new();
+        assert_eq!(tracker.track(u64::MAX), Arrival::Invalid);
+        assert_eq!(tracker.track(u64::MAX - 1), Arrival::InOrder);
+        assert_eq!(tracker.track(u64::MAX), Arrival::Invalid);
+        assert_eq!(tracker.stats(), SeqStats { received: 1, ..Default::default() });
+    }
+}
@@ -8,7 +8,11 @@ extern crate slog;
 use std::thread;
 use std::time::*;
 use std::str::FromStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use slog::Drain;
+use signal_hook::consts::{SIGINT, SIGTERM};
 
 fn main() {
     let args: clap::ArgMatches = clap::App::new("server")
@@ -28,7 +32,89 @@ fn main() {
              .value_name("ADDR")
              .validator(http_benchmarks::validate_socket_addr)
              .takes_value(true)
-             .required(true)) // until other server types implemented
+             .required(false))
+        .arg(clap::Arg::with_name("hyper-server")
+             .long("hyper-server")
+             .short("y")
+             .help("launch the hyper server, listening on <ADDR>")
+             .value_name("ADDR")
+             .validator(http_benchmarks::validate_socket_addr)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("std-server")
+             .long("std-server")
+             .short("s")
+             .help("launch the blocking std::net (thread per connection) server, listening on <ADDR>")
+             .value_name("ADDR")
+             .validator(http_benchmarks::validate_socket_addr)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("busy-poll-server")
+             .long("busy-poll-server")
+             .short("b")
+             .help("launch the single-threaded, busy-polling mio server, listening on <ADDR>")
+             .value_name("ADDR")
+             .validator(http_benchmarks::validate_socket_addr)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("h2-server")
+             .long("h2-server")
+             .short("2")
+             .help("launch the HTTP/2 server, listening on <ADDR> for prior-knowledge h2c and, with \
+                   --tls-cert and --tls-key, h2 over TLS")
+             .value_name("ADDR")
+             .validator(http_benchmarks::validate_socket_addr)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("tls-cert")
+             .long("tls-cert")
+             .help("PEM file of the certificate (chain) to terminate TLS with, in the hyper, std and \
+                   h2 servers (the others serve plain http)")
+             .value_name("PATH")
+             .requires("tls-key")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("tls-key")
+             .long("tls-key")
+             .help("PEM file of the private key to terminate TLS with")
+             .value_name("PATH")
+             .requires("tls-cert")
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("echo-timestamps")
+             .long("echo-timestamps")
+             .short("e")
+             .help("include the time each request was received and the time its response was sent \
+                   in the response headers, allowing clients to split the round trip"))
+        .group(clap::ArgGroup::with_name("servers")
+             .args(&["tokio-server", "hyper-server", "std-server", "busy-poll-server", "h2-server"])
+             .multiple(true)
+             .required(true))
+        .arg(clap::Arg::with_name("cores")
+             .long("cores")
+             .help("pin each server's thread, and the threads it spawns, to these cores, round robin \
+                   in the order tokio, hyper, std, busy-poll, h2 (of those launched; e.g. '0,1'). \
+                   requires building with the `affinity` feature")
+             .value_name("LIST")
+             .use_delimiter(true)
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("hist-core")
+             .long("hist-core")
+             .help("pin the histogram writer threads to this core (requires building with the \
+                   `affinity` feature)")
+             .value_name("CORE")
+             .validator(http_benchmarks::validate_uint)
+             .takes_value(true)
+             .required(false))
+        .arg(clap::Arg::with_name("summary-json")
+             .long("summary-json")
+             .help("on exit, save the latency percentiles, count and throughput of every tag, by \
+                   server, as json to <PATH> (the tables are logged either way)")
+             .value_name("PATH")
+             .takes_value(true)
+             .required(false))
         .arg(clap::Arg::with_name("interval")
              .long("interval")
              .short("i")
@@ -37,14 +123,38 @@ fn main() {
              .default_value("30")
              .validator(http_benchmarks::validate_uint)
              .takes_value(true)
-             .required(false)) // until other server types implemented
-
+             .required(false))
         .get_matches();
 
     let hist_dir = args.value_of("hist-dir").unwrap();
     let interval = u64::from_str(args.value_of("interval").unwrap()).unwrap();
     let interval = Duration::from_secs(interval);
 
+    let settings = http_benchmarks::servers::Settings {
+        hist_dir: hist_dir.to_string(),
+        interval,
+        echo_timestamps: args.is_present("echo-timestamps"),
+        tls: args.value_of("tls-cert").map(|cert| {
+            http_benchmarks::servers::Tls {
+                cert: cert.to_string(),
+                key: args.value_of("tls-key").unwrap().to_string(),
+            }
+        }),
+        core: None,
+        hist_core: args.value_of("hist-core").map(|core| usize::from_str(core).unwrap()),
+    };
+
+    // each server launched takes the next of `--cores`
+    let cores: Vec<usize> = args.values_of("cores")
+        .map(|cores| cores.map(|core| usize::from_str(core).unwrap()).collect())
+        .unwrap_or_default();
+    let mut n_launched = 0;
+    let mut server_settings = || {
+        let core = if cores.is_empty() { None } else { Some(cores[n_launched % cores.len()]) };
+        n_launched += 1;
+        http_benchmarks::servers::Settings { core, ..settings.clone() }
+    };
+
     let decorator = slog_term::TermDecorator::new().stdout().force_color().build();
     let drain = slog_term::CompactFormat::new(decorator).use_utc_timestamp().build().fuse();
     let drain = slog_async::Async::new(drain).chan_size(8192).thread_name("recv".into()).build().fuse();
@@ -55,15 +165,71 @@ fn main() {
     ));
     let logger = root.new(o!("thread" => "main"));
 
+    // the first SIGINT/SIGTERM stops the servers, a second one exits right away
+    let stop = Arc::new(AtomicBool::new(false));
+    for &signal in &[SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop)).unwrap();
+        signal_hook::flag::register(signal, Arc::clone(&stop)).unwrap();
+    }
+
     let tokio_server = args.value_of("tokio-server").map(|addr| {
         info!(logger, "launching tokio minihttp server, listening at {}", addr);
-        http_benchmarks::servers::tokio_server(addr, hist_dir, interval, &root)
+        http_benchmarks::servers::tokio_server(addr, Arc::clone(&stop), &server_settings(), &root)
+    });
+
+    let hyper_server = args.value_of("hyper-server").map(|addr| {
+        info!(logger, "launching hyper server, listening at {}", addr);
+        http_benchmarks::servers::hyper_server(addr, Arc::clone(&stop), &server_settings(), &root)
     });
 
-    info!(logger, "program initialized. press ctrl-c to exit.");
+    let std_server = args.value_of("std-server").map(|addr| {
+        info!(logger, "launching std::net server, listening at {}", addr);
+        http_benchmarks::servers::std_server(addr, Arc::clone(&stop), &server_settings(), &root)
+    });
+
+    let busy_poll_server = args.value_of("busy-poll-server").map(|addr| {
+        info!(logger, "launching busy-poll server, listening at {}", addr);
+        http_benchmarks::servers::busy_poll_server(addr, Arc::clone(&stop), &server_settings(), &root)
+    });
+
+    let h2_server = args.value_of("h2-server").map(|addr| {
+        info!(logger, "launching h2 server, listening at {}", addr);
+        http_benchmarks::servers::h2_server(addr, Arc::clone(&stop), &server_settings(), &root)
+    });
+
+    info!(logger, "program initialized. press ctrl-c to stop the servers (twice to exit at once).");
+
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    info!(logger, "stopping servers...");
+
+    let servers = vec![
+        ("tokio minihttp", tokio_server),
+        ("hyper", hyper_server),
+        ("std::net", std_server),
+        ("busy-poll", busy_poll_server),
+        ("h2", h2_server),
+    ];
+    let mut reports = Vec::new();
+    for (name, server) in servers {
+        if let Some(server) = server {
+            match server.join() {
+                Ok(report) => {
+                    info!(logger, "stopped {} server", name);
+                    reports.extend(report);
+                }
+                Err(_) => error!(logger, "{} server panicked", name),
+            }
+        }
+    }
+    info!(logger, "all servers stopped, histogram logs flushed");
 
-    loop {
-        thread::sleep(Duration::from_millis(1));
+    if let Some(path) = args.value_of("summary-json") {
+        match http_benchmarks::report::write_json(Path::new(path), &reports) {
+            Ok(()) => info!(logger, "saved latency summary"; "path" => path),
+            Err(e) => error!(logger, "failed to save latency summary: {:?}", e),
+        }
     }
 }
 
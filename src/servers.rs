@@ -1,28 +1,343 @@
 use futures::future;
 use tokio_minihttp::{Request, Response, Http};
-use tokio_proto::TcpServer;
 use tokio_service::Service;
 
 use std::thread;
 use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::net::{TcpListener, TcpStream, Shutdown};
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::time::{Instant, Duration};
+use std::path::{Path, PathBuf};
 use std::str;
 use std::io::{self, Read, Write};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use slog::{Drain, Logger};
 use chrono::{DateTime, Utc};
 use histlog::HistLog;
+use serde::Serialize;
+use openssl::ssl::{AlpnError, SslAcceptor, SslFiletype, SslMethod};
+use tokio_io::{AsyncRead, AsyncWrite};
 
-use crate::client_tag;
+use crate::{affinity, client_tag, wire};
+use crate::affinity::Pinned;
+use crate::sequence::{Arrival, SeqStats, SeqTracker};
+use crate::report::{Report, SummaryJson};
+
+/// Settings shared by every server.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// directory to save histogram log files in
+    pub hist_dir: String,
+    /// interval between writing log entries
+    pub interval: Duration,
+    /// add the time each request was received and the time its response was sent to
+    /// the response headers (see `wire::RECV_HEADER`, `wire::SEND_HEADER`)
+    pub echo_timestamps: bool,
+    /// certificate and key to terminate TLS with (servers that support it: hyper, std
+    /// and h2)
+    pub tls: Option<Tls>,
+    /// core to pin the server thread to, along with the threads it spawns
+    pub core: Option<usize>,
+    /// core to pin the histogram writer thread to
+    pub hist_core: Option<usize>,
+}
+
+/// PEM files of the certificate (chain) and private key a server terminates TLS with.
+#[derive(Clone, Debug)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+}
+
+/// ALPN protocol of the http/1.1 servers, in wire format.
+const ALPN_HTTP1: &[u8] = b"\x08http/1.1";
+
+impl Tls {
+    /// Builds an acceptor for these files, selecting the ALPN protocol `alpn` (in wire
+    /// format, e.g. `b"\x02h2"`).
+    pub fn acceptor(&self, alpn: &'static [u8]) -> Result<SslAcceptor, openssl::error::ErrorStack> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_certificate_chain_file(&self.cert)?;
+        builder.set_private_key_file(&self.key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+        builder.set_alpn_select_callback(move |_, protos| {
+            openssl::ssl::select_next_proto(alpn, protos).ok_or(AlpnError::NOACK)
+        });
+        Ok(builder.build())
+    }
+}
+
+/// Per-client histograms of a server, owned by a single thread (see `Recorder` for the
+/// shared version).
+///
+/// Decodes the body of each benchmark request (see `wire`) and records the lag
+/// between the server's clock and the client's timestamp, tagged by `client_tag`.
+///
+/// Also tracks the sequence numbers of each client run to detect lost, duplicated
+/// and reordered requests, logging the counts per tag at each histogram interval
+/// and in the summary written by `finish`.
+pub(crate) struct ByClient {
+    /// empty once finished
+    map: HashMap<&'static str, HistLog>,
+    seqs: HashMap<(&'static str, u32), SeqTracker>,
+    /// tags with a protocol suffix, by client tag and protocol
+    protocol_tags: HashMap<(&'static str, &'static str), &'static str>,
+    /// of the interval log
+    path: PathBuf,
+    logger: Logger,
+}
+
+impl ByClient {
+    pub(crate) fn new(hist_dir: &str, series: &'static str, interval: Duration, logger: &Logger) -> Self {
+        let hist = HistLog::new(hist_dir, series, "master", interval).unwrap();
+        let mut map: HashMap<&'static str, HistLog> = Default::default();
+        let path = hist.path().to_path_buf();
+        map.insert("master", hist);
+        Self { map, seqs: Default::default(), protocol_tags: Default::default(), path, logger: logger.clone() }
+    }
+
+    /// Path of the interval log.
+    pub(crate) fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    /// Sequence counts of all client runs tagged `tag`.
+    pub(crate) fn seq_stats(&self, tag: &str) -> SeqStats {
+        sum_seq_stats(&self.seqs, tag)
+    }
+
+    /// Tags recorded under, sorted.
+    fn tags(&self) -> Vec<&'static str> {
+        let mut tags: Vec<&'static str> = self.seqs.keys().map(|&(tag, _)| tag).collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
+    /// Logs the sequence counts of every tag.
+    pub(crate) fn log_summary(&self) {
+        for tag in self.tags() {
+            let stats = self.seq_stats(tag);
+            info!(self.logger, "summary"; "tag" => tag, "received" => stats.received, "missing" => stats.missing,
+                  "duplicates" => stats.duplicates, "out_of_order" => stats.out_of_order);
+        }
+    }
+
+    /// Ends the run: flushes every histogram to the interval log, reads it back into
+    /// a latency summary, and logs the sequence counts and latency percentiles of
+    /// every tag and saves them next to the log, as `<series>.<time>.summary.json`.
+    /// Requests recorded afterwards are ignored, and calling it again does nothing
+    /// (returning `None`).
+    ///
+    /// Called when dropped, if not before.
+    pub(crate) fn finish(&mut self) -> Option<Report> {
+        if self.map.is_empty() { return None }
+        // the last `HistLog` dropped writes the final interval and waits for the
+        // writer thread to finish
+        self.map.clear();
+        info!(self.logger, "flushed histogram log"; "path" => self.path.display().to_string());
+        self.log_summary();
+        let report = match Report::read(&self.path) {
+            Ok(report) => {
+                info!(self.logger, "latency summary\n{}", report);
+                Some(report)
+            }
+            Err(e) => {
+                error!(self.logger, "failed to read back the interval log: {:?}", e);
+                None
+            }
+        };
+        #[derive(Serialize)]
+        struct Json {
+            tags: BTreeMap<&'static str, SeqStats>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            latency: Option<BTreeMap<String, SummaryJson>>,
+        }
+        let json = Json {
+            tags: self.tags().into_iter().map(|tag| (tag, self.seq_stats(tag))).collect(),
+            latency: report.as_ref().map(Report::tags_json),
+        };
+        match crate::write_json(&self.path, "summary.json", &json) {
+            Ok(path) => info!(self.logger, "saved summary"; "path" => path.display().to_string()),
+            Err(e) => error!(self.logger, "failed to save summary: {:?}", e),
+        }
+        report
+    }
+
+    /// Decodes the request `body` (see `wire::Body`) received at `time` (nanoseconds
+    /// since the unix epoch) and records the one-way latency under the client's tag.
+    pub(crate) fn record(&mut self, body: &[u8], time: i64, loop_time: Instant) -> Result<(), wire::DecodeError> {
+        self.record_as(body, time, loop_time, None)
+    }
+
+    /// Like `record`, with the tag suffixed by the `protocol` the request arrived
+    /// over (e.g. "h2+tls[openssl]:h2"), for servers speaking more than one.
+    pub(crate) fn record_protocol(&mut self, body: &[u8], time: i64, loop_time: Instant, protocol: &'static str) -> Result<(), wire::DecodeError> {
+        self.record_as(body, time, loop_time, Some(protocol))
+    }
+
+    fn record_as(&mut self, body: &[u8], time: i64, loop_time: Instant, protocol: Option<&'static str>) -> Result<(), wire::DecodeError> {
+        let ByClient { map, seqs, protocol_tags, logger, .. } = self;
+        if map.is_empty() {
+            warn!(logger, "request after the histograms were flushed, not recorded");
+            return Ok(())
+        }
+        let body = wire::Body::decode(body).map_err(|e| {
+            error!(logger, "failed to decode body: {}", e; "body" => String::from_utf8_lossy(body).into_owned());
+            e
+        })?;
+        let key = client_tag(body.code).ok_or_else(|| {
+            error!(logger, "client code not found"; "code" => body.code);
+            wire::DecodeError::Field("code")
+        })?;
+        let key = match protocol {
+            Some(protocol) => *protocol_tags.entry((key, protocol))
+                .or_insert_with(|| crate::leak_tag(format!("{}:{}", key, protocol))),
+            None => key,
+        };
+
+        if !map.contains_key(&key) {
+            info!(logger, "inserting new key"; "key" => key);
+            let hist = map.get("master").unwrap().clone_with_tag(key);
+            map.insert(key, hist);
+        }
+
+        let hist = map.get_mut(key).unwrap();
+        // `sent` is any u64 the client put in the body
+        let nanos = (time.max(0) as u64).saturating_sub(body.sent);
+        hist.record(nanos).ok();
+        debug!(logger, "successfully recorded request"; "sent" => body.sent, "nanos" => nanos, "seq" => body.seq, "conn" => body.conn);
+
+        let tracker = seqs.entry((key, wire::run_id(body.conn))).or_insert_with(SeqTracker::new);
+        match tracker.track(body.seq) {
+            Arrival::InOrder => {}
+            Arrival::Gap(n) => warn!(logger, "sequence gap"; "tag" => key, "missing" => n, "seq" => body.seq, "conn" => body.conn),
+            Arrival::Duplicate => warn!(logger, "duplicate request"; "tag" => key, "seq" => body.seq, "conn" => body.conn),
+            Arrival::Late => warn!(logger, "out of order request"; "tag" => key, "seq" => body.seq, "conn" => body.conn),
+            Arrival::Invalid => warn!(logger, "invalid sequence number"; "tag" => key, "seq" => body.seq, "conn" => body.conn),
+        }
+
+        if hist.check_send(loop_time) {
+            info!(logger, "sequence counts"; "tag" => key, "counts" => %sum_seq_stats(seqs, key));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ByClient {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn sum_seq_stats(seqs: &HashMap<(&'static str, u32), SeqTracker>, tag: &str) -> SeqStats {
+    let mut stats = SeqStats::default();
+    for tracker in seqs.iter().filter(|(&(t, _), _)| t == tag).map(|(_, tracker)| tracker) {
+        stats += tracker.stats();
+    }
+    stats
+}
+
+/// `ByClient` shared by every connection (and thread) of a server.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    by_client: Arc<Mutex<ByClient>>,
+}
+
+impl Recorder {
+    pub(crate) fn new(by_client: ByClient) -> Self {
+        Self { by_client: Arc::new(Mutex::new(by_client)) }
+    }
+
+    /// See `ByClient::record`.
+    pub(crate) fn record(&self, body: &[u8], time: i64, loop_time: Instant) -> Result<(), wire::DecodeError> {
+        self.by_client.lock().unwrap().record(body, time, loop_time)
+    }
+
+    /// See `ByClient::finish`.
+    pub(crate) fn finish(&self) -> Option<Report> {
+        self.by_client.lock().unwrap().finish()
+    }
+}
+
+/// Creates the histograms of server `series`, on a thread pinned to
+/// `settings.hist_core` if given, so that the log's writer thread is pinned there too.
+fn new_by_client(settings: &Settings, series: &'static str, logger: &Logger) -> (ByClient, Vec<Pinned>) {
+    let (hist_dir, interval, hist_logger) = (settings.hist_dir.clone(), settings.interval, logger.clone());
+    let new = move || ByClient::new(&hist_dir, series, interval, &hist_logger);
+    match settings.hist_core {
+        Some(core) => {
+            let (by_client, pinned) = affinity::spawn_pinned("histlog", core, logger, new);
+            (by_client, vec![pinned])
+        }
+        None => (new(), Vec::new()),
+    }
+}
+
+/// Called first thing on a server's thread: pins it to `settings.core`, if given, and
+/// saves the run metadata next to the interval log at `log`, including the pinning
+/// applied.
+fn start_server(settings: &Settings, mut pinned: Vec<Pinned>, log: &Path, logger: &Logger) {
+    pinned.extend(settings.core.map(|core| affinity::pin("server", core, logger)));
+    match crate::write_meta(log, &pinned) {
+        Ok(path) => info!(logger, "saved run metadata"; "path" => path.display().to_string()),
+        Err(e) => error!(logger, "failed to save run metadata: {:?}", e),
+    }
+}
+
+/// How often servers blocked on io check their `stop` flag.
+const STOP_CHECK: Duration = Duration::from_millis(10);
+
+/// How long a stopped server waits for its connections to finish the requests in
+/// flight and close, before closing them regardless.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Resolves once `stop` is set. Polled on a tokio runtime (or tokio-core `Core`),
+/// which drives the timer.
+fn stopped(stop: Arc<AtomicBool>) -> impl future::Future<Item = (), Error = ()> {
+    use futures::Stream;
+
+    tokio::timer::Interval::new_interval(STOP_CHECK)
+        .map_err(|_| ())
+        .take_while(move |_| Ok(!stop.load(Ordering::Relaxed)))
+        .for_each(|_| Ok(()))
+}
+
+/// Resolves once none of the connections, each holding a clone of `open`, remain,
+/// or after `DRAIN_TIMEOUT`, with the number still open.
+fn drained(open: Arc<()>) -> impl future::Future<Item = usize, Error = ()> {
+    use futures::{Future, Stream};
+
+    let open = Arc::downgrade(&open);
+    let remaining = open.clone();
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    tokio::timer::Interval::new_interval(STOP_CHECK)
+        .map_err(|_| ())
+        .take_while(move |_| Ok(remaining.strong_count() > 0 && Instant::now() < deadline))
+        .for_each(|_| Ok(()))
+        .map(move |()| open.strong_count())
+}
+
+/// Logs the outcome of draining a stopped server's connections.
+fn log_drained(still_open: usize, logger: &Logger) {
+    if still_open == 0 {
+        info!(logger, "connections drained");
+    } else {
+        warn!(logger, "closing connections still open after drain timeout"; "n" => still_open, "timeout" => ?DRAIN_TIMEOUT);
+    }
+}
 
 #[derive(Clone)]
 struct TokioServer {
-    by_client: Arc<Mutex<HashMap<&'static str, HistLog>>>,
+    recorder: Recorder,
+    echo_timestamps: bool,
+    /// held for as long as the connection is open (see `drained`)
+    _open: Arc<()>,
     logger: Logger,
 }
 
@@ -39,78 +354,1102 @@ impl Service for TokioServer {
 
         trace!(self.logger, "new {} request to {}", req.method(), req.path());
 
-        req.body().or_else(|| {
-            error!(self.logger, "no body found"; "slice" => String::from_utf8(req.data().to_vec()).unwrap());
-            None
-        }).map(|body| {
-            bytes!(b' ').find(body).or_else(|| {
-                error!(self.logger, "no space found in body";
-                       "slice" => String::from_utf8(body[..].to_vec()).unwrap(),
-                       "data" => String::from_utf8(req.data().to_vec()).unwrap());
-                None
-            }).map(|i| {
-                // try to parse the code as u16
-                atoi::atoi::<u16>(&body[..i]).or_else(|| {
-                    error!(self.logger, "failed to parse strat code"; "slice" => String::from_utf8(body[..i].to_vec()).unwrap());
-                    None
-                }).and_then(|n| {
-                   client_tag(n)
-                       .or_else(|| {
-                           error!(self.logger, "strat not found"; "n" => n);
-                           None
-                       })
-                }).map(|key| {
-                    let mut map = self.by_client.lock().unwrap();
-
-                    if !map.contains_key(&key) {
-                        info!(self.logger, "inserting new key"; "key" => key);
-                        let hist = map.get("master").unwrap().clone_with_tag(key);
-                        map.insert(key, hist);
+        let mut resp = Response::new();
+        let calibration = req.path() == wire::CALIBRATION_PATH;
+        match if calibration { Ok(()) } else { self.recorder.record(req.body().unwrap_or(&[]), time, loop_time) } {
+            Ok(()) => {
+                resp.status_code(204, "No Content")
+                    .body("");
+                trace!(self.logger, "sending (future) 204 resp");
+            }
+
+            Err(_) => {
+                resp.status_code(400, "Bad Request")
+                    .body("");
+                trace!(self.logger, "sending (future) 400 resp");
+            }
+        }
+        if self.echo_timestamps || calibration {
+            resp.header(wire::RECV_HEADER, &time.to_string())
+                .header(wire::SEND_HEADER, &crate::nanos(Utc::now()).to_string());
+        }
+        future::ok(resp)
+    }
+}
+
+
+/// Serves until `stop` is set, then stops accepting connections, gives those open
+/// `DRAIN_TIMEOUT` to finish, and flushes the histograms (see `ByClient::finish`).
+/// The other servers do the same.
+pub fn tokio_server<A: ToSocketAddrs>(
+    addr: A,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Option<Report>> {
+    use futures::{Future, Stream};
+    use tokio_proto::BindServer;
+
+    let addr: SocketAddr = addr.to_socket_addrs().unwrap().next().unwrap();
+    let logger = logger.new(o!("thread" => "tokio-server"));
+    if settings.tls.is_some() {
+        warn!(logger, "TLS is not supported by the tokio minihttp server, serving plain http");
+    }
+    let (by_client, pinned) = new_by_client(settings, "tokio_server", &logger);
+    let log = by_client.path();
+    let recorder = Recorder::new(by_client);
+    let echo_timestamps = settings.echo_timestamps;
+    let settings = settings.clone();
+
+    thread::spawn(move || {
+        start_server(&settings, pinned, &log, &logger);
+        // what `tokio_proto::TcpServer` does on one thread, which serves forever
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let listener = tokio_core::net::TcpListener::bind(&addr, &handle).unwrap();
+        info!(logger, "accepting connections"; "addr" => %addr);
+        let open = Arc::new(());
+        let server = TokioServer { recorder: recorder.clone(), echo_timestamps, _open: Arc::clone(&open), logger: logger.clone() };
+        let err_logger = logger.clone();
+        let accept = listener.incoming()
+            .for_each(move |(stream, _)| {
+                Http.bind_server(&handle, stream, server.clone());
+                Ok(())
+            })
+            .map_err(move |e| {
+                error!(err_logger, "failed to accept connection: {:?}", e);
+            });
+        // dropping the listener along with `accept`
+        core.run(accept.select(stopped(stop)).map(|_| ()).map_err(|_| ())).ok();
+        info!(logger, "stopped accepting connections");
+        log_drained(core.run(drained(open)).unwrap(), &logger);
+        drop(core);
+        recorder.finish()
+    })
+}
+
+/// Same measurement as `tokio_server`, behind hyper's http stack.
+pub fn hyper_server<A: ToSocketAddrs>(
+    addr: A,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Option<Report>> {
+    use hyper::{Body, Server, StatusCode};
+    use hyper::rt::{Future, Stream};
+    use hyper::service::service_fn;
+
+    let addr: SocketAddr = addr.to_socket_addrs().unwrap().next().unwrap();
+    let logger = logger.new(o!("thread" => "hyper-server"));
+    let (by_client, pinned) = new_by_client(settings, "hyper_server", &logger);
+    let log = by_client.path();
+    let recorder = Recorder::new(by_client);
+    let echo_timestamps = settings.echo_timestamps;
+    let acceptor = settings.tls.as_ref().map(|tls| {
+        tls.acceptor(ALPN_HTTP1).map_err(|e| {
+            error!(logger, "failed to build SslAcceptor: {:?}", e);
+        }).expect("SslAcceptor")
+    });
+    let settings = settings.clone();
+
+    thread::spawn(move || {
+        // before the runtime spawns its threads, which inherit the pinning
+        start_server(&settings, pinned, &log, &logger);
+        info!(logger, "spawning hyper Server thread"; "tls" => acceptor.is_some());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let err_logger = logger.clone();
+        let conn_logger = logger.clone();
+        let service_logger = logger.clone();
+        let service_recorder = recorder.clone();
+        // hyper makes a service per connection, which holds a clone of `open`
+        let open = Arc::new(());
+        let service_open = Arc::clone(&open);
+        let new_service = move || {
+            let recorder = service_recorder.clone();
+            let logger = service_logger.clone();
+            let open = Arc::clone(&service_open);
+            service_fn(move |req: hyper::Request<Body>| {
+                // for the service to own `open`
+                let _ = &open;
+                trace!(logger, "new {} request to {}", req.method(), req.uri().path());
+                let calibration = req.uri().path() == wire::CALIBRATION_PATH;
+                let recorder = recorder.clone();
+                let logger = logger.clone();
+                req.into_body().concat2().map(move |body| {
+                    // timestamp after the full body has arrived, like the tokio
+                    // minihttp server (which is called with the complete request)
+                    let loop_time = Instant::now();
+                    let time = crate::nanos(Utc::now()) as i64;
+                    let status = match if calibration { Ok(()) } else { recorder.record(&body, time, loop_time) } {
+                        Ok(()) => StatusCode::NO_CONTENT,
+                        Err(_) => StatusCode::BAD_REQUEST,
+                    };
+                    trace!(logger, "sending (future) {} resp", status);
+                    let mut resp = hyper::Response::builder();
+                    resp.status(status);
+                    if echo_timestamps || calibration {
+                        resp.header(wire::RECV_HEADER, time.to_string())
+                            .header(wire::SEND_HEADER, crate::nanos(Utc::now()).to_string());
                     }
+                    resp.body(Body::empty()).unwrap()
+                })
+            })
+        };
+        match acceptor {
+            None => {
+                // stops accepting once `stop` is set, and closes each connection
+                // after its request in flight
+                let server = Server::bind(&addr)
+                    .serve(new_service)
+                    .with_graceful_shutdown(stopped(Arc::clone(&stop)))
+                    .map(|()| 0)
+                    .map_err(move |e| {
+                        error!(err_logger, "hyper server error: {:?}", e);
+                    });
+                let timeout = stopped(stop).and_then(move |()| drained(open));
+                let still_open = rt.block_on(server.select(timeout).map(|(n, _)| n).map_err(|_| ()));
+                log_drained(still_open.unwrap_or(0), &logger);
+            }
 
-                    let hist = map.get_mut(key).unwrap();
+            Some(acceptor) => {
+                use tokio_openssl::SslAcceptorExt;
 
-                    atoi::atoi::<i64>(&body[(i+1)..]).or_else(|| {
-                        error!(self.logger, "failed to parse timestamp"; "slice" => String::from_utf8(body[(i+1)..].to_vec()).unwrap());
-                        None
-                    }).map(|sent| {
-                        hist.record((time - sent).max(0) as u64);
-                        debug!(self.logger, "successfully recorded request"; "sent" => sent, "nanos" => (time - sent).max(0) as u64);
+                // hyper's `Server` takes a stream of connections, which would hold
+                // up accepting new ones during each handshake; instead, serve each
+                // connection in its own task once its handshake completes
+                let http = hyper::server::conn::Http::new();
+                let listener = tokio::net::TcpListener::bind(&addr).unwrap();
+                let conn_stop = Arc::clone(&stop);
+                let server = listener.incoming()
+                    .map_err(move |e| {
+                        error!(err_logger, "failed to accept connection: {:?}", e);
+                    })
+                    .for_each(move |stream| {
+                        stream.set_nodelay(true).ok();
+                        let service = new_service();
+                        let http = http.clone();
+                        let stop = Arc::clone(&conn_stop);
+                        let tls_logger = conn_logger.clone();
+                        let err_logger = conn_logger.clone();
+                        tokio::spawn(acceptor.accept_async(stream)
+                            .map_err(move |e| {
+                                warn!(tls_logger, "tls handshake failed: {:?}", e);
+                            })
+                            .and_then(move |stream| {
+                                // like the `Server` above: once stopped, close the
+                                // connection after its request in flight
+                                let mut conn = http.serve_connection(stream, service);
+                                let mut stopped = Some(stopped(stop));
+                                future::poll_fn(move || {
+                                    if stopped.as_mut().is_some_and(|s| s.poll() != Ok(futures::Async::NotReady)) {
+                                        stopped = None;
+                                        conn.graceful_shutdown();
+                                    }
+                                    conn.poll()
+                                }).map_err(move |e| {
+                                    warn!(err_logger, "connection error: {:?}", e);
+                                })
+                            }));
+                        Ok(())
                     });
+                // dropping the listener along with `server`
+                rt.block_on(server.select(stopped(stop)).map(|_| ()).map_err(|_| ())).ok();
+                info!(logger, "stopped accepting connections");
+                log_drained(rt.block_on(drained(open)).unwrap(), &logger);
+            }
+        }
+        rt.shutdown_now().wait().ok();
+        recorder.finish()
+    })
+}
 
-                    hist.check_send(loop_time);
-                });
-            });
+const BAD_REQUEST_CLOSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
 
-        });
-        let mut resp = Response::new();
-        resp.status_code(204, "No Content")
-            .body("");
-        trace!(self.logger, "sending (future) 204 resp");
-        future::ok(resp)
+/// upper bound on the length of a response written by `write_http1_response`
+const MAX_RESPONSE_LEN: usize = 256;
+
+/// Writes the response of the hand-rolled http/1.1 servers to a request, depending on
+/// whether it was recorded and whether the connection is kept alive. `echo` is the
+/// time the request was received, if echoing timestamps.
+fn write_http1_response<W: Write>(mut wtr: W, recorded: bool, keep_alive: bool, echo: Option<i64>) -> io::Result<()> {
+    wtr.write_all(if recorded { b"HTTP/1.1 204 No Content\r\n" } else { b"HTTP/1.1 400 Bad Request\r\n" })?;
+    if let Some(time) = echo {
+        for &(name, value) in &[(wire::RECV_HEADER, time as u64), (wire::SEND_HEADER, crate::nanos(Utc::now()))] {
+            wtr.write_all(name.as_bytes())?;
+            wtr.write_all(b": ")?;
+            itoa::write(&mut wtr, value)?;
+            wtr.write_all(b"\r\n")?;
+        }
+    }
+    if keep_alive {
+        wtr.write_all(b"Content-Length: 0\r\nConnection: keep-alive\r\n\r\n")
+    } else {
+        wtr.write_all(b"Content-Length: 0\r\nConnection: close\r\n\r\n")
     }
 }
 
+/// A complete http/1.1 request found at the start of a buffer by `parse_request`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParsedRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub body: &'a [u8],
+    pub keep_alive: bool,
+    /// number of bytes of the buffer the request occupies
+    pub len: usize,
+}
+
+/// Minimal http/1.1 request parser for the `std_server`.
+///
+/// Returns `Ok(None)` if `buf` does not hold a complete request yet. Empty lines
+/// preceding the request line are skipped (RFC 7230, section 3.5).
+pub(crate) fn parse_request(buf: &[u8]) -> Result<Option<ParsedRequest<'_>>, &'static str> {
+    let skip = buf.iter().take_while(|&&b| b == b'\r' || b == b'\n').count();
+    let head_end = match jetscii::ByteSubstring::new(b"\r\n\r\n").find(&buf[skip..]) {
+        Some(i) => skip + i,
+        None => return Ok(None),
+    };
+    let head = str::from_utf8(&buf[skip..head_end]).map_err(|_| "request head is not utf-8")?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, path, version) = match (request_line.next(), request_line.next(), request_line.next(), request_line.next()) {
+        (Some(method), Some(path), Some(version), None) if !method.is_empty() && !path.is_empty() => (method, path, version),
+        _ => return Err("malformed request line"),
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err("unsupported http version"),
+    };
+    let mut content_length = 0;
+    for line in lines {
+        let i = line.find(':').ok_or("malformed header")?;
+        let (name, value) = (&line[..i], line[(i + 1)..].trim());
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().map_err(|_| "invalid content-length")?;
+        } else if name.eq_ignore_ascii_case("connection") {
+            if value.eq_ignore_ascii_case("close") {
+                keep_alive = false;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                keep_alive = true;
+            }
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err("transfer-encoding not supported")
+        }
+    }
+    let body_start = head_end + 4;
+    let len = body_start.checked_add(content_length).ok_or("invalid content-length")?;
+    if buf.len() < len { return Ok(None) }
+    Ok(Some(ParsedRequest {
+        method,
+        path,
+        body: &buf[body_start..len],
+        keep_alive,
+        len,
+    }))
+}
 
-pub fn tokio_server<A: ToSocketAddrs>(
+/// Baseline server built only on `std::net`: blocking io, one thread per connection,
+/// and a hand-rolled http/1.1 parser (`parse_request`). The floor the async servers
+/// should be compared against.
+///
+/// Once stopped, the read side of each connection is shut down, so its thread
+/// answers the request it is handling and exits.
+pub fn std_server<A: ToSocketAddrs>(
     addr: A,
-    hist_dir: &str,
-    interval: Duration,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
     logger: &Logger,
-) -> thread::JoinHandle<()> {
-    let hist = HistLog::new(hist_dir, "tokio_server", "master", interval).unwrap();
-    let mut by_client: HashMap<&'static str, HistLog> = Default::default();
-    by_client.insert("master", hist);
-    let by_client = Arc::new(Mutex::new(by_client));
+) -> thread::JoinHandle<Option<Report>> {
     let addr: SocketAddr = addr.to_socket_addrs().unwrap().next().unwrap();
-    let logger = logger.new(o!("thread" => "tokio-server"));
+    let logger = logger.new(o!("thread" => "std-server"));
+    let (by_client, pinned) = new_by_client(settings, "std_server", &logger);
+    let log = by_client.path();
+    let recorder = Recorder::new(by_client);
+    let echo_timestamps = settings.echo_timestamps;
+    let acceptor = settings.tls.as_ref().map(|tls| {
+        tls.acceptor(ALPN_HTTP1).map_err(|e| {
+            error!(logger, "failed to build SslAcceptor: {:?}", e);
+        }).expect("SslAcceptor")
+    });
+    let listener = TcpListener::bind(addr).unwrap();
+    let settings = settings.clone();
+
+    // `accept` blocks: once stopped, connect to wake it up
+    let mut wake_addr = listener.local_addr().unwrap();
+    if wake_addr.ip().is_unspecified() {
+        wake_addr.set_ip(if wake_addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+    }
+    let waker_stop = Arc::clone(&stop);
+    let waker = thread::spawn(move || {
+        while !waker_stop.load(Ordering::Relaxed) {
+            thread::sleep(STOP_CHECK);
+        }
+        TcpStream::connect(wake_addr).ok();
+    });
 
     thread::spawn(move || {
-        info!(logger, "spawning TcpServer thread");
-        TcpServer::new(Http, addr)
-            .serve(move || {
-                let server = TokioServer { by_client: by_client.clone(), logger: logger.clone() };
-                Ok(server)
+        // connection threads inherit the pinning of the accepting thread
+        start_server(&settings, pinned, &log, &logger);
+        info!(logger, "accepting connections"; "addr" => %addr, "tls" => acceptor.is_some());
+        // a handle to each open connection, to shut it down once stopped
+        let open: Arc<Mutex<HashMap<usize, TcpStream>>> = Default::default();
+        for (i, stream) in listener.incoming().enumerate() {
+            if stop.load(Ordering::Relaxed) { break }
+            match stream {
+                Ok(stream) => {
+                    match stream.try_clone() {
+                        Ok(handle) => { open.lock().unwrap().insert(i, handle); }
+                        Err(e) => warn!(logger, "failed to clone stream, it will not be drained: {:?}", e),
+                    }
+                    let open = Arc::clone(&open);
+                    let recorder = recorder.clone();
+                    let acceptor = acceptor.clone();
+                    let logger = logger.new(o!("peer" => stream.peer_addr().map(|x| x.to_string()).unwrap_or_default()));
+                    thread::spawn(move || {
+                        let result = stream.set_nodelay(true).and_then(|()| match acceptor {
+                            Some(acceptor) => {
+                                let stream = acceptor.accept(stream).map_err(|e| {
+                                    io::Error::other(format!("tls handshake failed: {:?}", e))
+                                })?;
+                                std_connection(stream, &recorder, echo_timestamps, &logger)
+                            }
+
+                            None => std_connection(stream, &recorder, echo_timestamps, &logger),
+                        });
+                        if let Err(e) = result {
+                            warn!(logger, "connection error: {:?}", e);
+                        }
+                        open.lock().unwrap().remove(&i);
+                    });
+                }
+
+                Err(e) => error!(logger, "failed to accept connection: {:?}", e),
+            }
+        }
+        drop(listener);
+        waker.join().unwrap();
+        info!(logger, "stopped accepting connections");
+
+        for stream in open.lock().unwrap().values() {
+            stream.shutdown(Shutdown::Read).ok();
+        }
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while !open.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(STOP_CHECK);
+        }
+        let open = open.lock().unwrap();
+        for stream in open.values() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+        log_drained(open.len(), &logger);
+        recorder.finish()
+    })
+}
+
+fn std_connection<S: Read + Write>(mut stream: S, recorder: &Recorder, echo_timestamps: bool, logger: &Logger) -> io::Result<()> {
+    trace!(logger, "new connection");
+    let mut buf = [0u8; 4096];
+    let mut out = [0u8; MAX_RESPONSE_LEN];
+    let mut len = 0;
+    loop {
+        let n = stream.read(&mut buf[len..])?;
+        if n == 0 {
+            trace!(logger, "connection closed by peer");
+            return Ok(())
+        }
+        len += n;
+
+        let mut consumed = 0;
+        loop {
+            match parse_request(&buf[consumed..len]) {
+                Ok(Some(req)) => {
+                    let loop_time = Instant::now();
+                    let time = crate::nanos(Utc::now()) as i64;
+                    trace!(logger, "new {} request to {}", req.method, req.path);
+                    let calibration = req.path == wire::CALIBRATION_PATH;
+                    let recorded = calibration || recorder.record(req.body, time, loop_time).is_ok();
+                    consumed += req.len;
+                    let mut wtr = &mut out[..];
+                    write_http1_response(&mut wtr, recorded, req.keep_alive, Some(time).filter(|_| echo_timestamps || calibration))?;
+                    let n = MAX_RESPONSE_LEN - wtr.len();
+                    stream.write_all(&out[..n])?;
+                    if !req.keep_alive { return Ok(()) }
+                }
+
+                Ok(None) => break,
+
+                Err(e) => {
+                    warn!(logger, "bad request: {}", e;
+                          "data" => String::from_utf8_lossy(&buf[consumed..len]).into_owned());
+                    stream.write_all(BAD_REQUEST_CLOSE)?;
+                    return Ok(())
+                }
+            }
+        }
+
+        // shift any partial request to the front of the buffer
+        buf.copy_within(consumed..len, 0);
+        len -= consumed;
+
+        if len == buf.len() {
+            warn!(logger, "request exceeds buffer"; "len" => buf.len());
+            stream.write_all(BAD_REQUEST_CLOSE)?;
+            return Ok(())
+        }
+    }
+}
+
+/// Connection state of the `busy_poll_server`. Buffers are fixed size and allocated
+/// once per connection.
+struct BusyPollConn {
+    stream: mio::net::TcpStream,
+    rbuf: [u8; 4096],
+    rlen: usize,
+    wbuf: [u8; 1024],
+    wlen: usize,
+    close_after_write: bool,
+}
+
+impl BusyPollConn {
+    fn new(stream: mio::net::TcpStream) -> Self {
+        Self { stream, rbuf: [0u8; 4096], rlen: 0, wbuf: [0u8; 1024], wlen: 0, close_after_write: false }
+    }
+
+    /// Queues the response to a request (see `write_http1_response`), which must fit
+    /// in the write buffer.
+    fn queue(&mut self, recorded: bool, keep_alive: bool, echo: Option<i64>) -> io::Result<()> {
+        let mut wtr = &mut self.wbuf[self.wlen..];
+        let available = wtr.len();
+        write_http1_response(&mut wtr, recorded, keep_alive, echo)?;
+        self.wlen += available - wtr.len();
+        Ok(())
+    }
+
+    /// Writes as much of the write buffer as the socket accepts. Returns `Ok(false)`
+    /// once the connection should be closed.
+    fn flush(&mut self) -> io::Result<bool> {
+        let mut written = 0;
+        while written < self.wlen {
+            match self.stream.write(&self.wbuf[written..self.wlen]) {
+                Ok(0) => return Ok(false),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.wbuf.copy_within(written..self.wlen, 0);
+        self.wlen -= written;
+        Ok(!(self.close_after_write && self.wlen == 0))
+    }
+
+    /// Handles each complete request read so far, then reads until the socket would
+    /// block. Once the write buffer has no room for another response and the socket
+    /// takes no more of it, stops with the remaining requests left in the read buffer,
+    /// to be resumed on the next (writable) event. Returns `Ok(false)` once the
+    /// connection should be closed.
+    fn on_ready(&mut self, by_client: &mut ByClient, echo_timestamps: bool, logger: &Logger) -> io::Result<bool> {
+        loop {
+            let mut consumed = 0;
+            let mut blocked = false;
+            while !self.close_after_write {
+                if self.wbuf.len() - self.wlen < MAX_RESPONSE_LEN {
+                    if !self.flush()? { return Ok(false) }
+                    if self.wbuf.len() - self.wlen < MAX_RESPONSE_LEN {
+                        blocked = true;
+                        break
+                    }
+                }
+
+                let (recorded, echo) = match parse_request(&self.rbuf[consumed..self.rlen]) {
+                    Ok(Some(req)) => {
+                        let loop_time = Instant::now();
+                        let time = crate::nanos(Utc::now()) as i64;
+                        trace!(logger, "new {} request to {}", req.method, req.path);
+                        let calibration = req.path == wire::CALIBRATION_PATH;
+                        let recorded = calibration || by_client.record(req.body, time, loop_time).is_ok();
+                        consumed += req.len;
+                        self.close_after_write = !req.keep_alive;
+                        (recorded, Some(time).filter(|_| echo_timestamps || calibration))
+                    }
+
+                    Ok(None) => break,
+
+                    Err(e) => {
+                        warn!(logger, "bad request: {}", e;
+                              "data" => String::from_utf8_lossy(&self.rbuf[consumed..self.rlen]).into_owned());
+                        self.close_after_write = true;
+                        (false, None)
+                    }
+                };
+                self.queue(recorded, !self.close_after_write, echo)?;
+            }
+
+            self.rbuf.copy_within(consumed..self.rlen, 0);
+            self.rlen -= consumed;
+
+            if !self.flush()? { return Ok(false) }
+            if blocked || self.close_after_write { return Ok(true) }
+
+            if self.rlen == self.rbuf.len() {
+                warn!(logger, "request exceeds buffer"; "len" => self.rbuf.len());
+                return Ok(false)
+            }
+
+            match self.stream.read(&mut self.rbuf[self.rlen..]) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.rlen += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Single-threaded server built on `mio` that busy-polls its sockets instead of
+/// parking the thread, reuses fixed per-connection buffers, and records to
+/// histograms owned by the server thread (no `Arc<Mutex<_>>`).
+///
+/// Once stopped, connections are closed as soon as they have no request in progress.
+pub fn busy_poll_server<A: ToSocketAddrs>(
+    addr: A,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Option<Report>> {
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+
+    const LISTENER: Token = Token(usize::MAX - 1);
+
+    let addr: SocketAddr = addr.to_socket_addrs().unwrap().next().unwrap();
+    let logger = logger.new(o!("thread" => "busy-poll-server"));
+    if settings.tls.is_some() {
+        warn!(logger, "TLS is not supported by the busy-poll server, serving plain http");
+    }
+    let (mut by_client, pinned) = new_by_client(settings, "busy_poll_server", &logger);
+    let echo_timestamps = settings.echo_timestamps;
+    let listener = mio::net::TcpListener::bind(&addr).unwrap();
+    let settings = settings.clone();
+
+    thread::spawn(move || {
+        start_server(&settings, pinned, &by_client.path(), &logger);
+        let poll = Poll::new().unwrap();
+        poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge()).unwrap();
+        let mut events = Events::with_capacity(1024);
+        let mut conns: Vec<Option<BusyPollConn>> = Vec::new();
+        info!(logger, "accepting connections"; "addr" => %addr);
+        let mut listener = Some(listener);
+        let mut drain_deadline = None;
+
+        loop {
+            if drain_deadline.is_none() && stop.load(Ordering::Relaxed) {
+                listener = None;
+                drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
+                info!(logger, "stopped accepting connections");
+            }
+
+            // zero timeout: spin rather than park the thread
+            poll.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+
+            for event in events.iter() {
+                if event.token() == LISTENER {
+                    while let Some(ref listener) = listener {
+                        match listener.accept() {
+                            Ok((stream, peer)) => {
+                                stream.set_nodelay(true).ok();
+                                let i = conns.iter().position(|c| c.is_none()).unwrap_or_else(|| {
+                                    conns.push(None);
+                                    conns.len() - 1
+                                });
+                                poll.register(&stream, Token(i), Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
+                                conns[i] = Some(BusyPollConn::new(stream));
+                                trace!(logger, "new connection"; "peer" => %peer, "token" => i);
+                            }
+
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+
+                            Err(e) => {
+                                error!(logger, "failed to accept connection: {:?}", e);
+                                break
+                            }
+                        }
+                    }
+                    continue
+                }
+
+                let Token(i) = event.token();
+                let keep = match conns.get_mut(i).and_then(|c| c.as_mut()) {
+                    Some(conn) => conn.on_ready(&mut by_client, echo_timestamps, &logger).unwrap_or_else(|e| {
+                        warn!(logger, "connection error: {:?}", e);
+                        false
+                    }),
+
+                    None => continue,
+                };
+
+                if !keep {
+                    if let Some(conn) = conns[i].take() {
+                        poll.deregister(&conn.stream).ok();
+                        trace!(logger, "connection closed"; "token" => i);
+                    }
+                }
+            }
+
+            if let Some(deadline) = drain_deadline {
+                for conn in conns.iter_mut() {
+                    if conn.as_ref().is_some_and(|c| c.rlen == 0 && c.wlen == 0) {
+                        poll.deregister(&conn.take().unwrap().stream).ok();
+                    }
+                }
+                let still_open = conns.iter().flatten().count();
+                if still_open == 0 || Instant::now() >= deadline {
+                    log_drained(still_open, &logger);
+                    break
+                }
+            }
+        }
+        by_client.finish()
+    })
+}
+
+/// Serves the h2 requests of one connection, spawning a task per stream so a slow
+/// stream doesn't hold up the others. Once `stop` is set, sends GOAWAY and closes
+/// the connection after the streams in flight.
+fn h2_connection<T>(
+    io: T,
+    protocol: &'static str,
+    by_client: Rc<RefCell<ByClient>>,
+    echo_timestamps: bool,
+    stop: Arc<AtomicBool>,
+    logger: Logger,
+) -> impl future::Future<Item = (), Error = ()>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    use futures::{Future, Stream};
+
+    let err_logger = logger.clone();
+    h2::server::handshake(io)
+        .and_then(move |mut connection| {
+            trace!(logger, "new h2 connection"; "protocol" => protocol);
+            let mut stopped = Some(stopped(stop));
+            futures::stream::poll_fn(move || {
+                if stopped.as_mut().is_some_and(|s| s.poll() != Ok(futures::Async::NotReady)) {
+                    stopped = None;
+                    connection.graceful_shutdown();
+                }
+                connection.poll()
+            }).for_each(move |(req, mut respond)| {
+                trace!(logger, "new {} request to {}", req.method(), req.uri().path());
+                let calibration = req.uri().path() == wire::CALIBRATION_PATH;
+                let by_client = Rc::clone(&by_client);
+                let logger = logger.clone();
+                let mut body = req.into_body();
+                let mut buf = Vec::new();
+                let request = future::poll_fn(move || {
+                    while let Some(chunk) = futures::try_ready!(body.poll()) {
+                        body.release_capacity().release_capacity(chunk.len())?;
+                        buf.extend_from_slice(&chunk);
+                    }
+                    Ok(futures::Async::Ready(mem::take(&mut buf)))
+                }).and_then(move |body| {
+                    // timestamp after the full body has arrived, like the hyper server
+                    let loop_time = Instant::now();
+                    let time = crate::nanos(Utc::now()) as i64;
+                    let recorded = calibration
+                        || by_client.borrow_mut().record_protocol(&body, time, loop_time, protocol).is_ok();
+                    let mut resp = http::Response::builder();
+                    resp.status(if recorded { 204 } else { 400 });
+                    if echo_timestamps || calibration {
+                        resp.header(wire::RECV_HEADER, time.to_string())
+                            .header(wire::SEND_HEADER, crate::nanos(Utc::now()).to_string());
+                    }
+                    respond.send_response(resp.body(()).unwrap(), true).map(|_| ())
+                }).map_err(move |e| {
+                    warn!(logger, "h2 stream error: {:?}", e);
+                });
+                tokio::runtime::current_thread::spawn(request);
+                Ok(())
+            })
+        })
+        .map_err(move |e| {
+            warn!(err_logger, "h2 connection error: {:?}", e);
+        })
+}
+
+/// HTTP/2 server on a single thread, accepting both prior-knowledge h2c and (if
+/// `settings.tls` is set) h2 over TLS on the same port, told apart by the first byte
+/// each connection sends.
+///
+/// One-way latency is recorded per stream, tagged with the client's tag suffixed by
+/// the protocol: ":h2c" or ":h2".
+pub fn h2_server<A: ToSocketAddrs>(
+    addr: A,
+    stop: Arc<AtomicBool>,
+    settings: &Settings,
+    logger: &Logger,
+) -> thread::JoinHandle<Option<Report>> {
+    use futures::{Future, Stream};
+
+    /// first byte of a TLS record carrying a handshake message (the ClientHello)
+    const TLS_HANDSHAKE: u8 = 0x16;
+
+    let addr: SocketAddr = addr.to_socket_addrs().unwrap().next().unwrap();
+    let logger = logger.new(o!("thread" => "h2-server"));
+    let acceptor = settings.tls.as_ref().map(|tls| {
+        tls.acceptor(b"\x02h2").map_err(|e| {
+            error!(logger, "failed to build SslAcceptor: {:?}", e);
+        }).expect("SslAcceptor")
+    });
+    let (by_client, pinned) = new_by_client(settings, "h2_server", &logger);
+    let settings = settings.clone();
+
+    thread::spawn(move || {
+        start_server(&settings, pinned, &by_client.path(), &logger);
+        info!(logger, "spawning h2 server thread"; "tls" => acceptor.is_some());
+        let by_client = Rc::new(RefCell::new(by_client));
+        let echo_timestamps = settings.echo_timestamps;
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let listener = tokio::net::TcpListener::bind(&addr).unwrap();
+        info!(logger, "accepting connections"; "addr" => %addr);
+        let err_logger = logger.clone();
+        let conn_logger = logger.clone();
+        let conn_stop = Arc::clone(&stop);
+        let conn_by_client = Rc::clone(&by_client);
+        let open = Arc::new(());
+        let conn_open = Arc::clone(&open);
+        let server = listener.incoming()
+            .map_err(move |e| {
+                error!(err_logger, "failed to accept connection: {:?}", e);
+            })
+            .for_each(move |stream| {
+                stream.set_nodelay(true).ok();
+                let acceptor = acceptor.clone();
+                let by_client = Rc::clone(&conn_by_client);
+                let stop = Arc::clone(&conn_stop);
+                let open = Arc::clone(&conn_open);
+                let logger = conn_logger.clone();
+                let mut stream = Some(stream);
+                let peek_logger = logger.clone();
+                let conn = future::poll_fn(move || {
+                    let mut first = [0u8; 1];
+                    futures::try_ready!(stream.as_mut().unwrap().poll_peek(&mut first));
+                    Ok(futures::Async::Ready((stream.take().unwrap(), first[0])))
+                }).map_err(move |e: io::Error| {
+                    warn!(peek_logger, "connection error: {:?}", e);
+                }).and_then(move |(stream, first)| -> Box<dyn Future<Item = (), Error = ()>> {
+                    match (first, acceptor) {
+                        (TLS_HANDSHAKE, Some(acceptor)) => {
+                            use tokio_openssl::SslAcceptorExt;
+                            let err_logger = logger.clone();
+                            Box::new(acceptor.accept_async(stream)
+                                .map_err(move |e| {
+                                    warn!(err_logger, "tls handshake failed: {:?}", e);
+                                })
+                                .and_then(move |stream| h2_connection(stream, "h2", by_client, echo_timestamps, stop, logger)))
+                        }
+
+                        (TLS_HANDSHAKE, None) => {
+                            warn!(logger, "TLS connection, but no --tls-cert/--tls-key given; closing");
+                            Box::new(future::ok(()))
+                        }
+
+                        _ => Box::new(h2_connection(stream, "h2c", by_client, echo_timestamps, stop, logger)),
+                    }
+                }).then(move |result| {
+                    drop(open);
+                    result
+                });
+                tokio::runtime::current_thread::spawn(conn);
+                Ok(())
             });
+        // dropping the listener along with `server`
+        rt.block_on(server.select(stopped(stop)).map(|_| ()).map_err(|_| ())).ok();
+        info!(logger, "stopped accepting connections");
+        log_drained(rt.block_on(drained(open)).unwrap(), &logger);
+        drop(rt);
+        let report = by_client.borrow_mut().finish();
+        report
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_valid_bodies_only() {
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        let logger = Logger::root(slog::Discard, o!());
+        let mut by_client = ByClient::new(hist_dir.to_str().unwrap(), "test_server", Duration::from_secs(30), &logger);
+        let time = crate::nanos(Utc::now()) as i64;
+        assert_eq!(by_client.record(b"v1 11 1571234567891234567 0 0", time, Instant::now()), Ok(()));
+        assert_eq!(by_client.record(b"11 1571234567891234567", time, Instant::now()), Err(wire::DecodeError::Version));
+        assert_eq!(by_client.record(b"v1 999 1571234567891234567 0 0", time, Instant::now()), Err(wire::DecodeError::Field("code")));
+        assert_eq!(by_client.record(b"", time, Instant::now()), Err(wire::DecodeError::Empty));
+        assert_eq!(by_client.record(format!("v1 11 {} 0 0", i64::MAX as u64 + 1).as_bytes(), time, Instant::now()), Ok(()));
+    }
+
+    #[test]
+    fn tracks_sequence_per_client_run() {
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        let logger = Logger::root(slog::Discard, o!());
+        let mut by_client = ByClient::new(hist_dir.to_str().unwrap(), "test_server", Duration::from_secs(30), &logger);
+        let time = crate::nanos(Utc::now()) as i64;
+        let conn_a = wire::conn_id(1, 1);
+        let conn_b = wire::conn_id(2, 1);
+        for &(seq, conn) in &[(0, conn_a), (0, conn_b), (1, conn_a), (3, wire::conn_id(1, 2)), (1, conn_b), (1, conn_b)] {
+            let body = format!("v1 11 {} {} {}", time, seq, conn);
+            by_client.record(body.as_bytes(), time, Instant::now()).unwrap();
+        }
+        assert_eq!(by_client.seq_stats("raw-tcp"), SeqStats { received: 5, missing: 1, duplicates: 1, out_of_order: 0 });
+    }
+
+    #[test]
+    fn tags_by_protocol() {
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        let logger = Logger::root(slog::Discard, o!());
+        let mut by_client = ByClient::new(hist_dir.to_str().unwrap(), "test_server", Duration::from_secs(30), &logger);
+        let time = crate::nanos(Utc::now()) as i64;
+        for seq in 0..3 {
+            let body = format!("v1 13 {} {} 0", time, seq);
+            by_client.record_protocol(body.as_bytes(), time, Instant::now(), "h2c").unwrap();
+        }
+        by_client.record_protocol(b"v1 13 0 0 0", time, Instant::now(), "h2").unwrap();
+        assert_eq!(by_client.seq_stats("h2+tls[openssl]:h2c").received, 3);
+        assert_eq!(by_client.seq_stats("h2+tls[openssl]:h2").received, 1);
+        assert_eq!(by_client.seq_stats("h2+tls[openssl]").received, 0);
+    }
+
+    /// Sends one h2 request with `body` over `io`, returning the response status.
+    fn h2_request<T>(rt: &mut tokio::runtime::current_thread::Runtime, io: T, body: &'static [u8]) -> u16
+        where T: AsyncRead + AsyncWrite + 'static
+    {
+        use futures::Future;
+
+        let (mut send, connection) = rt.block_on(h2::client::handshake(io)).unwrap();
+        rt.spawn(connection.map_err(|_| ()));
+        let req = http::Request::post("http://localhost/rust-http-benchmarks/").body(()).unwrap();
+        let (resp, mut stream) = send.send_request(req, false).unwrap();
+        stream.send_data(body.into(), true).unwrap();
+        rt.block_on(resp).unwrap().status().as_u16()
+    }
+
+    #[test]
+    fn h2_server_accepts_h2c_and_tls() {
+        use futures::Future;
+        use tokio_openssl::SslConnectorExt;
+
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        let (cert, cert_path, key_path) = crate::testing::write_self_signed(&hist_dir, "h2-server");
+        let settings = Settings {
+            hist_dir: hist_dir.to_str().unwrap().to_string(),
+            interval: Duration::from_secs(30),
+            echo_timestamps: false,
+            tls: Some(Tls { cert: cert_path, key: key_path }),
+            core: None,
+            hist_core: None,
+        };
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let _server = h2_server(addr, Default::default(), &settings, &Logger::root(slog::Discard, o!()));
+        let connect = || {
+            for _ in 0..100 {
+                if let Ok(stream) = TcpStream::connect(addr) {
+                    return tokio::net::TcpStream::from_std(stream, &Default::default()).unwrap()
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("h2 server not listening")
+        };
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        assert_eq!(h2_request(&mut rt, connect(), b"v1 13 1571234567891234567 0 0"), 204);
+        assert_eq!(h2_request(&mut rt, connect(), b"not a benchmark request"), 400);
+
+        let mut connector = openssl::ssl::SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.cert_store_mut().add_cert(cert).unwrap();
+        connector.set_alpn_protos(b"\x02h2").unwrap();
+        let stream = rt.block_on(connector.build().connect_async("localhost", connect()).map_err(|e| format!("{:?}", e))).unwrap();
+        assert_eq!(stream.get_ref().ssl().selected_alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(h2_request(&mut rt, stream, b"v1 13 1571234567891234567 0 0"), 204);
+    }
+
+    /// Sends a benchmark request over TLS to a server listening on `addr`, trusting
+    /// `cert`, returning the response head.
+    fn https_request(addr: SocketAddr, cert: openssl::x509::X509) -> String {
+        let mut connector = openssl::ssl::SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.cert_store_mut().add_cert(cert).unwrap();
+        let connector = connector.build();
+        let stream = (0..100).filter_map(|_| {
+            TcpStream::connect(addr).map_err(|_| thread::sleep(Duration::from_millis(10))).ok()
+        }).next().expect("server not listening");
+        let mut stream = connector.connect("localhost", stream).unwrap();
+        let body = format!("v1 11 {} 0 0", crate::nanos(Utc::now()));
+        write!(stream, "POST /rust-http-benchmarks/ HTTP/1.1\r\nHost: localhost\r\n\
+                        Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        let mut resp = Vec::new();
+        let mut buf = [0u8; 512];
+        while !resp.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed");
+            resp.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(resp).unwrap()
+    }
+
+    #[test]
+    fn terminates_tls() {
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+        let (cert, cert_path, key_path) = crate::testing::write_self_signed(&hist_dir, "https-server");
+        let settings = Settings {
+            hist_dir: hist_dir.to_str().unwrap().to_string(),
+            interval: Duration::from_secs(30),
+            echo_timestamps: false,
+            tls: Some(Tls { cert: cert_path, key: key_path }),
+            core: None,
+            hist_core: None,
+        };
+        let logger = Logger::root(slog::Discard, o!());
+        for &server in &[std_server::<SocketAddr> as fn(_, _, &_, &_) -> _, hyper_server::<SocketAddr>] {
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let _server = server(addr, Default::default(), &settings, &logger);
+            let resp = https_request(addr, cert.clone());
+            assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", resp);
+        }
+    }
+
+    #[test]
+    fn stopping_flushes_histograms_and_writes_summary() {
+        let logger = Logger::root(slog::Discard, o!());
+        let servers = [
+            ("tokio", tokio_server::<SocketAddr> as fn(_, _, &_, &_) -> _),
+            ("hyper", hyper_server::<SocketAddr>),
+            ("std", std_server::<SocketAddr>),
+            ("busy-poll", busy_poll_server::<SocketAddr>),
+        ];
+        for &(name, server) in &servers {
+            let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test").join(format!("stop-{}", name));
+            std::fs::remove_dir_all(&hist_dir).ok();
+            std::fs::create_dir_all(&hist_dir).unwrap();
+            let settings = Settings {
+                hist_dir: hist_dir.to_str().unwrap().to_string(),
+                interval: Duration::from_secs(30),
+                echo_timestamps: false,
+                tls: None,
+                core: None,
+                hist_core: None,
+            };
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let stop = Arc::new(AtomicBool::new(false));
+            let handle = server(addr, Arc::clone(&stop), &settings, &logger);
+            let mut stream = (0..100).filter_map(|_| {
+                TcpStream::connect(addr).map_err(|_| thread::sleep(Duration::from_millis(10))).ok()
+            }).next().expect("server not listening");
+            // in one write: the tokio minihttp server doesn't wait for the rest of a body
+            let body = format!("v1 11 {} 0 0", crate::nanos(Utc::now()));
+            let req = format!("POST /rust-http-benchmarks/ HTTP/1.1\r\nHost: localhost\r\n\
+                               Content-Length: {}\r\n\r\n{}", body.len(), body);
+            stream.write_all(req.as_bytes()).unwrap();
+            let mut resp = [0u8; 512];
+            let n = stream.read(&mut resp).unwrap();
+            assert!(resp[..n].starts_with(b"HTTP/1.1 204"), "{}: {}", name, String::from_utf8_lossy(&resp[..n]));
+
+            // the connection is left open, idle
+            stop.store(true, Ordering::Relaxed);
+            let report = handle.join().unwrap().expect("no latency summary");
+            assert!(TcpStream::connect(addr).is_err(), "{} server still accepting", name);
+            let tags: Vec<(&str, u64)> = report.tags.iter().map(|summary| (summary.tag.as_str(), summary.count())).collect();
+            assert_eq!(tags, vec![("raw-tcp", 1)], "{}", name);
+
+            let files: Vec<PathBuf> = std::fs::read_dir(&hist_dir).unwrap().map(|e| e.unwrap().path()).collect();
+            let summary = files.iter().find(|path| path.to_str().unwrap().ends_with(".summary.json"))
+                .unwrap_or_else(|| panic!("{}: no summary in {:?}", name, files));
+            let summary = std::fs::read_to_string(summary).unwrap();
+            assert!(summary.contains("\"tags\":{\"raw-tcp\":{\"received\":1,\"missing\":0"), "{}: {}", name, summary);
+            assert!(summary.contains("\"latency\":{\"raw-tcp\":{\"count\":1,"), "{}: {}", name, summary);
+            let log = std::fs::read_to_string(files.iter().find(|path| !path.to_str().unwrap().ends_with(".json")).unwrap()).unwrap();
+            assert!(log.contains("Tag=raw-tcp,"), "{}: final interval not flushed:\n{}", name, log);
+        }
+    }
+
+    #[test]
+    fn busy_poll_server_answers_deep_pipelines() {
+        let hist_dir = std::env::temp_dir().join("rust-http-benchmarks-test").join("busy-poll-pipeline");
+        std::fs::remove_dir_all(&hist_dir).ok();
+        let settings = Settings {
+            hist_dir: hist_dir.to_str().unwrap().to_string(),
+            interval: Duration::from_secs(30),
+            echo_timestamps: false,
+            tls: None,
+            core: None,
+            hist_core: None,
+        };
+        let logger = Logger::root(slog::Discard, o!());
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = busy_poll_server(addr, Arc::clone(&stop), &settings, &logger);
+        let stream = (0..100).filter_map(|_| {
+            TcpStream::connect(addr).map_err(|_| thread::sleep(Duration::from_millis(10))).ok()
+        }).next().expect("server not listening");
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        // each write holds more responses than the write buffer
+        const WRITES: usize = 200;
+        const PIPELINED: usize = 25;
+        let mut wtr = stream.try_clone().unwrap();
+        let writer = thread::spawn(move || {
+            let time = crate::nanos(Utc::now());
+            for i in 0..WRITES {
+                let reqs: String = (i * PIPELINED..(i + 1) * PIPELINED).map(|seq| {
+                    let body = format!("v1 11 {} {} 0", time, seq);
+                    format!("POST /rust-http-benchmarks/ HTTP/1.1\r\nHost: localhost\r\n\
+                             Content-Length: {}\r\n\r\n{}", body.len(), body)
+                }).collect();
+                wtr.write_all(reqs.as_bytes()).unwrap();
+            }
+        });
+        let response = b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\nConnection: keep-alive\r\n\r\n";
+        let (mut rdr, mut buf, mut received) = (&stream, [0u8; 4096], Vec::new());
+        while received.len() < WRITES * PIPELINED * response.len() {
+            let n = rdr.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed after {} responses", received.len() / response.len());
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert!(received.chunks(response.len()).all(|resp| resp == &response[..]));
+        writer.join().unwrap();
+
+        stop.store(true, Ordering::Relaxed);
+        let report = handle.join().unwrap().expect("no latency summary");
+        assert_eq!(report.tags[0].count(), (WRITES * PIPELINED) as u64);
+    }
+
+    #[test]
+    fn parses_benchmark_request() {
+        let buf = b"POST /rust-http-benchmarks/ HTTP/1.1\r\nHost: 127.0.0.1:34567\r\n\
+                    Connection: keep-alive\r\nContent-Length: 5\r\n\r\n11 42";
+        let req = parse_request(&buf[..]).unwrap().unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/rust-http-benchmarks/");
+        assert_eq!(req.body, b"11 42");
+        assert!(req.keep_alive);
+        assert_eq!(req.len, buf.len());
+        assert_eq!(parse_request(&buf[..(buf.len() - 1)]), Ok(None));
+    }
+
+    #[test]
+    fn parses_connection_semantics() {
+        let req = parse_request(b"GET / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert!(!req.keep_alive);
+        let req = parse_request(b"\r\n\r\nGET / HTTP/1.1\r\nconnection: Close\r\n\r\n").unwrap().unwrap();
+        assert!(!req.keep_alive);
+        assert!(req.body.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(parse_request(b"GET /\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/2.0\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nno colon\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! Helpers shared by the tests of several modules and the integration tests.
+
+use std::fs;
+use std::path::Path;
+use openssl::{asn1, bn, hash, pkey, rsa, x509};
+
+/// Self-signed certificate for "localhost".
+pub fn self_signed() -> (x509::X509, pkey::PKey<pkey::Private>) {
+    let key = pkey::PKey::from_rsa(rsa::Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = x509::X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut cert = x509::X509Builder::new().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&bn::BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&asn1::Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&asn1::Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = x509::extension::SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&cert.x509v3_context(None, None))
+        .unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, hash::MessageDigest::sha256()).unwrap();
+    (cert.build(), key)
+}
+
+/// Writes a self-signed certificate and its key as PEM files in `dir`, named after
+/// `name`, returning the paths of the certificate and key.
+pub fn write_self_signed(dir: &Path, name: &str) -> (x509::X509, String, String) {
+    let (cert, key) = self_signed();
+    fs::create_dir_all(dir).unwrap();
+    let cert_path = dir.join(format!("{}.cert.pem", name));
+    let key_path = dir.join(format!("{}.key.pem", name));
+    fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+    fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (cert, cert_path.to_str().unwrap().to_string(), key_path.to_str().unwrap().to_string())
+}
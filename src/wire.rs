@@ -0,0 +1,186 @@
+//! Body of the benchmark requests sent by the clients and decoded by the servers.
+//!
+//! The body is ascii, space separated, so requests remain readable in logs:
+//!
+//! ```text
+//! v1 <code> <sent> <seq> <conn>[ <padding>]
+//! ```
+//!
+//! - `code`: client type, see `client_tag`
+//! - `sent`: client timestamp when the request was sent, nanoseconds since the unix epoch
+//! - `seq`: request sequence number, counted per client run (across reconnects)
+//! - `conn`: id of the connection the request was sent on. The upper 32 bits identify
+//!   the client run `seq` belongs to, the lower 32 bits count its connections (see
+//!   `conn_id`)
+//! - `padding`: optional filler bytes, used to vary the request size
+//!
+//! The leading version token allows the format to change without the servers
+//! misinterpreting bodies sent by older clients.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::{self, FromStr};
+
+/// Response header holding the time the server received the request, in nanoseconds
+/// since the unix epoch (if the server echoes timestamps).
+pub const RECV_HEADER: &str = "X-Recv-Nanos";
+/// Response header holding the time the server sent the response, in nanoseconds
+/// since the unix epoch (if the server echoes timestamps).
+pub const SEND_HEADER: &str = "X-Send-Nanos";
+
+/// Path of the servers' clock calibration endpoint. Requests to it are answered with
+/// `RECV_HEADER` and `SEND_HEADER` (whether or not the server echoes timestamps
+/// otherwise) and are not recorded.
+pub const CALIBRATION_PATH: &str = "/rust-http-benchmarks/clock";
+
+/// Current version of the body format, sent as `v<VERSION>`.
+pub const VERSION: u8 = 1;
+
+const VERSION_TOKEN: &[u8] = b"v1";
+const PADDING: [u8; 64] = [b'x'; 64];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Body {
+    pub code: u16,
+    pub sent: u64,
+    pub seq: u64,
+    pub conn: u64,
+    /// number of padding bytes
+    pub padding: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    /// missing or unsupported version token
+    Version,
+    /// field missing or not a valid integer
+    Field(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty body"),
+            DecodeError::Version => write!(f, "missing or unsupported version (expected v{})", VERSION),
+            DecodeError::Field(name) => write!(f, "missing or invalid field: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Body {
+    /// Length of the encoded body in bytes (the request's `Content-Length`).
+    pub fn encoded_len(&self) -> usize {
+        let padding = if self.padding > 0 { 1 + self.padding } else { 0 };
+        VERSION_TOKEN.len() + 4 + digits(self.code as u64) + digits(self.sent) + digits(self.seq) + digits(self.conn) + padding
+    }
+
+    /// Writes the encoded body to `wtr`, returning the number of bytes written.
+    pub fn encode<W: Write>(&self, mut wtr: W) -> io::Result<usize> {
+        wtr.write_all(VERSION_TOKEN)?;
+        for &n in &[self.code as u64, self.sent, self.seq, self.conn] {
+            wtr.write_all(b" ")?;
+            itoa::write(&mut wtr, n)?;
+        }
+        if self.padding > 0 {
+            wtr.write_all(b" ")?;
+            let mut remaining = self.padding;
+            while remaining > 0 {
+                let n = remaining.min(PADDING.len());
+                wtr.write_all(&PADDING[..n])?;
+                remaining -= n;
+            }
+        }
+        Ok(self.encoded_len())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        if buf.is_empty() { return Err(DecodeError::Empty) }
+        let mut fields = buf.splitn(6, |&b| b == b' ');
+        if fields.next() != Some(VERSION_TOKEN) { return Err(DecodeError::Version) }
+        let code = parse_field(fields.next(), "code")?;
+        let sent = parse_field(fields.next(), "sent")?;
+        let seq = parse_field(fields.next(), "seq")?;
+        let conn = parse_field(fields.next(), "conn")?;
+        let padding = fields.next().map(|x| x.len()).unwrap_or(0);
+        Ok(Body { code, sent, seq, conn, padding })
+    }
+}
+
+/// Builds the `conn` field from the id of a client run and the number of the
+/// connection within that run.
+pub fn conn_id(run: u32, n: u32) -> u64 {
+    ((run as u64) << 32) | n as u64
+}
+
+/// Id of the client run a `conn` field belongs to.
+pub fn run_id(conn: u64) -> u32 {
+    (conn >> 32) as u32
+}
+
+/// Picks an id for a new client run, unlikely to collide with other runs.
+pub fn new_run_id() -> u32 {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static N_RUNS: AtomicUsize = AtomicUsize::new(0);
+    let nanos = crate::nanos(chrono::Utc::now());
+    let n = N_RUNS.fetch_add(1, Ordering::Relaxed) as u64;
+    let x = nanos ^ ((std::process::id() as u64) << 40) ^ n.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (x ^ (x >> 32)) as u32
+}
+
+fn parse_field<T: FromStr>(field: Option<&[u8]>, name: &'static str) -> Result<T, DecodeError> {
+    field.filter(|x| !x.is_empty() && x.iter().all(u8::is_ascii_digit))
+        .and_then(|x| str::from_utf8(x).ok())
+        .and_then(|x| x.parse().ok())
+        .ok_or(DecodeError::Field(name))
+}
+
+fn digits(mut n: u64) -> usize {
+    let mut digits = 1;
+    while n >= 10 {
+        n /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    #[test]
+    fn encodes_as_text() {
+        let body = Body { code: 11, sent: 1_571_234_567_891_234_567, seq: 42, conn: 7, padding: 3 };
+        let mut buf = Vec::new();
+        assert_eq!(body.encode(&mut buf).unwrap(), buf.len());
+        assert_eq!(&buf[..], &b"v1 11 1571234567891234567 42 7 xxx"[..]);
+    }
+
+    #[test]
+    fn rejects_bad_bodies() {
+        assert_eq!(Body::decode(b""), Err(DecodeError::Empty));
+        assert_eq!(Body::decode(b"11 1571234567891234567"), Err(DecodeError::Version));
+        assert_eq!(Body::decode(b"v2 11 1 2 3"), Err(DecodeError::Version));
+        assert_eq!(Body::decode(b"v1 11 1 2"), Err(DecodeError::Field("conn")));
+        assert_eq!(Body::decode(b"v1 70000 1 2 3"), Err(DecodeError::Field("code")));
+        assert_eq!(Body::decode(b"v1 11 1x 2 3"), Err(DecodeError::Field("sent")));
+        assert_eq!(Body::decode(b"v1 11 +1 2 3"), Err(DecodeError::Field("sent")));
+    }
+
+    quickcheck! {
+        fn roundtrip(code: u16, sent: u64, seq: u64, conn: u64, padding: u8) -> bool {
+            let body = Body { code, sent, seq, conn, padding: padding as usize };
+            let mut buf = Vec::new();
+            let n = body.encode(&mut buf).unwrap();
+            n == buf.len() && Body::decode(&buf) == Ok(body)
+        }
+
+        fn decode_never_panics(buf: Vec<u8>) -> bool {
+            let _ = Body::decode(&buf);
+            true
+        }
+    }
+}
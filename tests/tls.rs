@@ -0,0 +1,92 @@
+//! End to end: the openssl client against a server terminating TLS with a self-signed
+//! certificate for "localhost".
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use http_benchmarks::{clients, servers, testing};
+use slog::{o, Logger};
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join("rust-http-benchmarks-test");
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn logger() -> Logger {
+    Logger::root(slog::Discard, o!())
+}
+
+/// Starts a std server terminating TLS for "localhost", returning its port and the
+/// path of its certificate.
+fn tls_server(name: &str) -> (u16, String) {
+    let (_, cert, key) = testing::write_self_signed(&temp_dir(), name);
+    let settings = servers::Settings {
+        hist_dir: temp_dir().to_str().unwrap().to_string(),
+        interval: Duration::from_secs(30),
+        echo_timestamps: false,
+        tls: Some(servers::Tls { cert: cert.clone(), key }),
+        core: None,
+        hist_core: None,
+    };
+    // bound the same way the client connects: to the first address "localhost"
+    // resolves to
+    let port = TcpListener::bind("localhost:0").unwrap().local_addr().unwrap().port();
+    servers::std_server(("localhost", port), Default::default(), &settings, &logger());
+    (port, cert)
+}
+
+/// Runs the openssl client against `uri` for a moment, returning the number of
+/// successful responses it received.
+fn run_client(uri: &str, tls: clients::TlsOptions) -> usize {
+    let settings = clients::Settings {
+        throttle: Some(Duration::from_millis(1)),
+        hist_dir: temp_dir().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    let client = clients::openssl_client(uri.parse().unwrap(), tls, Arc::clone(&stop), &settings, &logger());
+    thread::sleep(Duration::from_millis(500));
+    stop.store(true, Ordering::Relaxed);
+    client.join().unwrap().responses.ok
+}
+
+#[test]
+fn verifies_server_named_in_uri() {
+    let (port, cert) = tls_server("uri-host");
+    let tls = clients::TlsOptions { ca_file: Some(cert), ..Default::default() };
+    assert!(run_client(&format!("https://localhost:{}", port), tls) > 0);
+}
+
+#[test]
+fn verifies_servername_override() {
+    let (port, cert) = tls_server("servername");
+    let addr = TcpListener::bind("localhost:0").unwrap().local_addr().unwrap().ip();
+    let uri = match addr {
+        std::net::IpAddr::V4(ip) => format!("https://{}:{}", ip, port),
+        std::net::IpAddr::V6(ip) => format!("https://[{}]:{}", ip, port),
+    };
+    let tls = clients::TlsOptions {
+        ca_file: Some(cert.clone()),
+        servername: Some("localhost".to_string()),
+        ..Default::default()
+    };
+    assert!(run_client(&uri, tls) > 0);
+
+    // without the override, the address doesn't match the certificate
+    let tls = clients::TlsOptions { ca_file: Some(cert), ..Default::default() };
+    assert_eq!(run_client(&uri, tls), 0);
+}
+
+#[test]
+fn rejects_untrusted_certificate() {
+    let (port, _) = tls_server("untrusted");
+    assert_eq!(run_client(&format!("https://localhost:{}", port), Default::default()), 0);
+    let tls = clients::TlsOptions { insecure: true, ..Default::default() };
+    assert!(run_client(&format!("https://localhost:{}", port), tls) > 0);
+}